@@ -0,0 +1,454 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
+};
+
+use crate::{
+    export_util::{ExportFormat, ExportOptions, export_image},
+    image_editor::{SUPPORTED_EXTENSIONS, apply_pipeline, load_image, require_fonts},
+    preset::load_preset,
+};
+
+const USAGE: &str = "\
+Usage: image-effects-dnd batch --preset <FILE> --out <DIR> [OPTIONS] <INPUT>...
+
+Applies a saved effect preset to every input and writes the results to <DIR>.
+Inputs can be files, directories (their supported images, non-recursive) or
+glob patterns such as \"photos/*.jpg\".
+
+Options:
+  -p, --preset <FILE>       Preset saved from the editor (.ieffects or .ron)
+  -o, --out <DIR>           Output directory, created if missing
+  -n, --name <TEMPLATE>     Output file name [default: {stem}_edited.{ext}]
+                            {stem}  input file name without extension
+                            {ext}   extension of the output format
+                            {index} 1-based position in the input list
+                            Names that clash get a -2, -3, ... suffix
+                            and must not contain path separators
+  -f, --format <FORMAT>     png, jpeg, webp or tiff [default: input format, else png]
+  -q, --quality <1-100>     JPEG quality [default: 90]
+  -c, --compression <0-9>   PNG compression level [default: 6]
+  -h, --help                Print this help";
+
+struct BatchArgs {
+    preset: PathBuf,
+    out_dir: PathBuf,
+    name_template: String,
+    format: Option<ExportFormat>,
+    options: ExportOptions,
+    inputs: Vec<String>,
+}
+
+impl BatchArgs {
+    fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let mut preset = None;
+        let mut out_dir = None;
+        let mut name_template = "{stem}_edited.{ext}".to_string();
+        let mut format = None;
+        let mut options = ExportOptions::default();
+        let mut inputs = vec![];
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-p" | "--preset" => preset = Some(PathBuf::from(value()?)),
+                "-o" | "--out" => out_dir = Some(PathBuf::from(value()?)),
+                "-n" | "--name" => name_template = value()?.clone(),
+                "-f" | "--format" => {
+                    let name = value()?;
+                    format = Some(
+                        ExportFormat::from_extension(name)
+                            .ok_or_else(|| format!("unknown format {name}"))?,
+                    );
+                }
+                "-q" | "--quality" => {
+                    options.jpeg_quality = parse_number(value()?, 1..=100)?;
+                }
+                "-c" | "--compression" => {
+                    options.png_compression = parse_number(value()?, 0..=9)?;
+                }
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(format!("unknown option {flag}"));
+                }
+                input => inputs.push(input.to_string()),
+            }
+        }
+
+        if inputs.is_empty() {
+            return Err("no inputs given".to_string());
+        }
+        let sample = name_template
+            .replace("{stem}", "stem")
+            .replace("{ext}", "ext")
+            .replace("{index}", "1");
+        check_file_name(&sample).map_err(|_| {
+            format!("--name \"{name_template}\" must give a file name without path separators")
+        })?;
+        Ok(Some(Self {
+            preset: preset.ok_or("--preset is required")?,
+            out_dir: out_dir.ok_or("--out is required")?,
+            name_template,
+            format,
+            options,
+            inputs,
+        }))
+    }
+}
+
+fn parse_number(value: &str, range: std::ops::RangeInclusive<u8>) -> Result<u8, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|n| range.contains(n))
+        .ok_or_else(|| format!("{value} is not in {}..={}", range.start(), range.end()))
+}
+
+/// Entry point of the `batch` subcommand. `args` are the arguments following
+/// the subcommand name.
+pub(crate) fn run(args: &[String]) -> ExitCode {
+    let args = match BatchArgs::parse(args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let pipeline = match load_preset(&args.preset) {
+        Ok(pipeline) => pipeline,
+        Err(err) => {
+            eprintln!(
+                "error: could not load preset {}: {err}",
+                args.preset.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let missing_fonts = require_fonts(&pipeline);
+    if !missing_fonts.is_empty() {
+        for err in missing_fonts {
+            eprintln!("error: {err}");
+        }
+        return ExitCode::FAILURE;
+    }
+    if let Err(err) = fs::create_dir_all(&args.out_dir) {
+        eprintln!("error: could not create {}: {err}", args.out_dir.display());
+        return ExitCode::FAILURE;
+    }
+
+    // Inputs that resolve to nothing are reported, the others still run
+    let (inputs, input_errors) = expand_inputs(&args.inputs);
+    for err in &input_errors {
+        eprintln!("error: {err}");
+    }
+    let total = inputs.len();
+    let mut failed = 0;
+
+    let outputs = output_paths(&args, &inputs);
+    for (i, (input, output)) in inputs.iter().zip(outputs).enumerate() {
+        let progress = format!("[{}/{total}]", i + 1);
+        let result = output.and_then(|(output, options)| {
+            let img = load_image(input)?;
+            let img = apply_pipeline(img, &pipeline);
+            export_image(&img, &output, &options)?;
+            Ok(output)
+        });
+        match result {
+            Ok(output) => println!("{progress} {} -> {}", input.display(), output.display()),
+            Err(err) => {
+                failed += 1;
+                eprintln!("{progress} {}: {err}", input.display());
+            }
+        }
+    }
+
+    println!(
+        "{} of {total} files processed, {failed} failed",
+        total - failed
+    );
+    if failed > 0 || !input_errors.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Resolves files, directories and glob patterns into a list of image paths,
+/// plus an error for each directory or pattern that couldn't be read or
+/// matched nothing.
+fn expand_inputs(inputs: &[String]) -> (Vec<PathBuf>, Vec<String>) {
+    let mut paths = vec![];
+    let mut errors = vec![];
+    for input in inputs {
+        match expand_input(input) {
+            Ok(expanded) => paths.extend(expanded),
+            Err(err) => errors.push(err.to_string()),
+        }
+    }
+    (paths, errors)
+}
+
+fn expand_input(input: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let path = Path::new(input);
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(|err| format!("could not read {input}: {err}"))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_supported(path))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    } else if input.contains(['*', '?', '[']) {
+        let matches = glob::glob(input)
+            .map_err(|err| format!("bad pattern {input}: {err}"))?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Err(format!("{input} matched no files").into());
+        }
+        Ok(matches)
+    } else {
+        // Missing files are reported per file while processing
+        Ok(vec![path.to_path_buf()])
+    }
+}
+
+fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Output path and options for each input. Where the name template gives two
+/// inputs the same file, e.g. `a.png` and `a.jpg` with `--format png`, the
+/// later ones are numbered `a_edited-2.png`, `a_edited-3.png`, ...
+fn output_paths(
+    args: &BatchArgs,
+    inputs: &[PathBuf],
+) -> Vec<Result<(PathBuf, ExportOptions), Box<dyn Error>>> {
+    // Compared ignoring case, for case-insensitive file systems
+    let mut taken = HashSet::new();
+    let mut claim = |path: &Path| taken.insert(path.to_string_lossy().to_lowercase());
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            let (output, options) = output_path(args, input, i + 1)?;
+            if claim(&output) {
+                return Ok((output, options));
+            }
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = output.extension().map(|ext| ext.to_string_lossy());
+            let numbered = (2..)
+                .map(|n| {
+                    let name = match &ext {
+                        Some(ext) => format!("{stem}-{n}.{ext}"),
+                        None => format!("{stem}-{n}"),
+                    };
+                    output.with_file_name(name)
+                })
+                .find(|path| claim(path))
+                .expect("unbounded range");
+            Ok((numbered, options))
+        })
+        .collect()
+}
+
+fn output_path(
+    args: &BatchArgs,
+    input: &Path,
+    index: usize,
+) -> Result<(PathBuf, ExportOptions), Box<dyn Error>> {
+    let input_format = ExportFormat::from_path(input);
+    let format = args.format.or(input_format).unwrap_or(ExportFormat::Png);
+    // Keep the input's spelling of the extension (jpeg vs jpg) when the format stays the same
+    let ext = match input.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if input_format == Some(format) => ext.to_string(),
+        _ => format.extensions()[0].to_string(),
+    };
+    let stem = input
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or("input has no file name")?;
+
+    let name = args
+        .name_template
+        .replace("{stem}", stem)
+        .replace("{ext}", &ext)
+        .replace("{index}", &index.to_string());
+    check_file_name(&name)?;
+    let options = ExportOptions {
+        format,
+        ..args.options
+    };
+    Ok((args.out_dir.join(name), options))
+}
+
+/// Makes sure `name` stays inside the output directory: a single file name,
+/// without separators and not `.` or `..`.
+fn check_file_name(name: &str) -> Result<(), String> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+        _ => Err(format!("output name \"{name}\" is not a plain file name")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<BatchArgs>, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        BatchArgs::parse(&args)
+    }
+
+    fn parse_err(args: &[&str]) -> String {
+        parse(args).err().expect("parsing should fail")
+    }
+
+    fn output_names(args: &[&str], inputs: &[&str]) -> Vec<String> {
+        let args = parse(args).unwrap().unwrap();
+        let inputs: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+        output_paths(&args, &inputs)
+            .into_iter()
+            .map(|output| {
+                let (path, _) = output.unwrap();
+                assert_eq!(path.parent(), Some(Path::new("out")));
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_all_options() {
+        let args = parse(&[
+            "--preset",
+            "p.ieffects",
+            "-o",
+            "out",
+            "-n",
+            "{index}.{ext}",
+            "-f",
+            "jpg",
+            "-q",
+            "80",
+            "--compression",
+            "3",
+            "a.png",
+            "b/*.jpg",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(args.preset, Path::new("p.ieffects"));
+        assert_eq!(args.out_dir, Path::new("out"));
+        assert_eq!(args.name_template, "{index}.{ext}");
+        assert_eq!(args.format, Some(ExportFormat::Jpeg));
+        assert_eq!(args.options.jpeg_quality, 80);
+        assert_eq!(args.options.png_compression, 3);
+        assert_eq!(args.inputs, ["a.png", "b/*.jpg"]);
+    }
+
+    #[test]
+    fn help_stops_parsing() {
+        assert!(parse(&["a.png", "--help", "--bogus"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse_err(&["-o", "out", "a.png"]), "--preset is required");
+        assert_eq!(parse_err(&["-p", "p", "a.png"]), "--out is required");
+        assert_eq!(parse_err(&["-p", "p", "-o", "out"]), "no inputs given");
+        assert_eq!(parse_err(&["-p", "p", "-o"]), "missing value for -o");
+        assert_eq!(parse_err(&["--bogus", "a.png"]), "unknown option --bogus");
+        assert_eq!(parse_err(&["-f", "gif", "a.png"]), "unknown format gif");
+        assert_eq!(parse_err(&["-q", "0", "a.png"]), "0 is not in 1..=100");
+        assert_eq!(parse_err(&["-c", "x", "a.png"]), "x is not in 0..=9");
+    }
+
+    #[test]
+    fn rejects_templates_that_leave_the_output_directory() {
+        for template in [
+            "../{stem}.{ext}",
+            "sub/{stem}.{ext}",
+            "..",
+            "a\\b.{ext}",
+            "",
+        ] {
+            let err = parse_err(&["-p", "p", "-o", "out", "-n", template, "a.png"]);
+            assert_eq!(
+                err,
+                format!("--name \"{template}\" must give a file name without path separators")
+            );
+        }
+    }
+
+    #[test]
+    fn checks_output_names() {
+        assert!(check_file_name("a_edited.png").is_ok());
+        assert!(check_file_name("..png").is_ok());
+        for name in ["", ".", "..", "a/b.png", "/a.png", "a\\b.png"] {
+            assert!(check_file_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn numbers_clashing_output_names() {
+        let args = ["-p", "p", "-o", "out", "-f", "png", "x"];
+        assert_eq!(
+            output_names(&args, &["a.png", "b/a.jpg", "c/A.PNG", "b.png"]),
+            [
+                "a_edited.png",
+                "a_edited-2.png",
+                "A_edited-3.PNG",
+                "b_edited.png"
+            ]
+        );
+        // A numbered name can't take one that a later input gets naturally
+        let args = ["-p", "p", "-o", "out", "-n", "{stem}.{ext}", "x"];
+        assert_eq!(
+            output_names(&args, &["x/a.png", "y/a.png", "a-2.png"]),
+            ["a.png", "a-2.png", "a-2-2.png"]
+        );
+    }
+
+    #[test]
+    fn keeps_the_input_extension_spelling() {
+        let args = ["-p", "p", "-o", "out", "x"];
+        assert_eq!(
+            output_names(&args, &["a.jpeg", "b.tiff", "c.bmp"]),
+            ["a_edited.jpeg", "b_edited.tiff", "c_edited.png"]
+        );
+    }
+
+    #[test]
+    fn reports_unmatched_patterns_and_keeps_going() {
+        let dir = env::temp_dir().join(format!("batch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.png"), b"").unwrap();
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+        let dir_input = dir.to_string_lossy().into_owned();
+        let unmatched = dir.join("*.webp").to_string_lossy().into_owned();
+
+        let (paths, errors) =
+            expand_inputs(&[unmatched.clone(), dir_input, "missing.png".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(paths, [dir.join("a.png"), PathBuf::from("missing.png")]);
+        assert_eq!(errors, [format!("{unmatched} matched no files")]);
+    }
+}
@@ -0,0 +1,405 @@
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// How a layer's color combines with the color beneath it, as in image
+/// editors' layer styles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Add,
+    Subtract,
+    /// Hue of the layer, saturation and luminosity of the background.
+    Hue,
+    /// Saturation of the layer, hue and luminosity of the background.
+    Saturation,
+    /// Hue and saturation of the layer, luminosity of the background.
+    Color,
+    /// Luminosity of the layer, hue and saturation of the background.
+    Luminosity,
+}
+
+impl BlendMode {
+    pub(crate) const ALL: [BlendMode; 18] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::HardLight,
+        BlendMode::SoftLight,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Add,
+        BlendMode::Subtract,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::Screen => "Screen",
+            BlendMode::Overlay => "Overlay",
+            BlendMode::Darken => "Darken",
+            BlendMode::Lighten => "Lighten",
+            BlendMode::ColorDodge => "Color Dodge",
+            BlendMode::ColorBurn => "Color Burn",
+            BlendMode::HardLight => "Hard Light",
+            BlendMode::SoftLight => "Soft Light",
+            BlendMode::Difference => "Difference",
+            BlendMode::Exclusion => "Exclusion",
+            BlendMode::Add => "Add",
+            BlendMode::Subtract => "Subtract",
+            BlendMode::Hue => "Hue",
+            BlendMode::Saturation => "Saturation",
+            BlendMode::Color => "Color",
+            BlendMode::Luminosity => "Luminosity",
+        }
+    }
+
+    /// Blends the layer color `top` onto `bottom`, channels in 0.0..=1.0.
+    /// Formulas follow the W3C compositing spec.
+    pub(crate) fn blend(self, bottom: [f32; 3], top: [f32; 3]) -> [f32; 3] {
+        match self {
+            BlendMode::Hue => set_lum(set_sat(top, sat(bottom)), lum(bottom)),
+            BlendMode::Saturation => set_lum(set_sat(bottom, sat(top)), lum(bottom)),
+            BlendMode::Color => set_lum(top, lum(bottom)),
+            BlendMode::Luminosity => set_lum(bottom, lum(top)),
+            _ => std::array::from_fn(|c| self.blend_channel(bottom[c], top[c])),
+        }
+    }
+
+    /// Blends one channel of the layer `top` onto `bottom` for the modes
+    /// that treat channels separately.
+    fn blend_channel(self, bottom: f32, top: f32) -> f32 {
+        match self {
+            BlendMode::Normal => top,
+            BlendMode::Multiply => bottom * top,
+            BlendMode::Screen => bottom + top - bottom * top,
+            BlendMode::Overlay => BlendMode::HardLight.blend_channel(top, bottom),
+            BlendMode::Darken => bottom.min(top),
+            BlendMode::Lighten => bottom.max(top),
+            BlendMode::ColorDodge => {
+                if bottom == 0.0 {
+                    0.0
+                } else if top >= 1.0 {
+                    1.0
+                } else {
+                    (bottom / (1.0 - top)).min(1.0)
+                }
+            }
+            BlendMode::ColorBurn => {
+                if bottom >= 1.0 {
+                    1.0
+                } else if top <= 0.0 {
+                    0.0
+                } else {
+                    1.0 - ((1.0 - bottom) / top).min(1.0)
+                }
+            }
+            BlendMode::HardLight => {
+                if top <= 0.5 {
+                    BlendMode::Multiply.blend_channel(bottom, 2.0 * top)
+                } else {
+                    BlendMode::Screen.blend_channel(bottom, 2.0 * top - 1.0)
+                }
+            }
+            BlendMode::SoftLight => {
+                if top <= 0.5 {
+                    bottom - (1.0 - 2.0 * top) * bottom * (1.0 - bottom)
+                } else {
+                    let d = if bottom <= 0.25 {
+                        ((16.0 * bottom - 12.0) * bottom + 4.0) * bottom
+                    } else {
+                        bottom.sqrt()
+                    };
+                    bottom + (2.0 * top - 1.0) * (d - bottom)
+                }
+            }
+            BlendMode::Difference => (bottom - top).abs(),
+            BlendMode::Exclusion => bottom + top - 2.0 * bottom * top,
+            BlendMode::Add => (bottom + top).min(1.0),
+            BlendMode::Subtract => (bottom - top).max(0.0),
+            BlendMode::Hue | BlendMode::Saturation | BlendMode::Color | BlendMode::Luminosity => {
+                unreachable!("non-separable modes blend whole colors")
+            }
+        }
+    }
+}
+
+/// Luminosity as the W3C spec weighs it.
+fn lum([r, g, b]: [f32; 3]) -> f32 {
+    0.3 * r + 0.59 * g + 0.11 * b
+}
+
+/// Shifts `color` to luminosity `l`, pulling channels that end up out of
+/// range back towards the gray of that luminosity.
+fn set_lum(color: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(color);
+    let color = color.map(|c| c + d);
+    let l = lum(color);
+    let min = color.into_iter().fold(f32::INFINITY, f32::min);
+    let max = color.into_iter().fold(f32::NEG_INFINITY, f32::max);
+    if min < 0.0 {
+        color.map(|c| l + (c - l) * l / (l - min))
+    } else if max > 1.0 {
+        color.map(|c| l + (c - l) * (1.0 - l) / (max - l))
+    } else {
+        color
+    }
+}
+
+fn sat(color: [f32; 3]) -> f32 {
+    let min = color.into_iter().fold(f32::INFINITY, f32::min);
+    let max = color.into_iter().fold(f32::NEG_INFINITY, f32::max);
+    max - min
+}
+
+/// `color` rescaled to saturation `s`, keeping the order of its channels.
+fn set_sat(color: [f32; 3], s: f32) -> [f32; 3] {
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| color[a].total_cmp(&color[b]));
+    let [min, mid, max] = order;
+    let mut result = [0.0; 3];
+    if color[max] > color[min] {
+        result[mid] = (color[mid] - color[min]) * s / (color[max] - color[min]);
+        result[max] = s;
+    }
+    result
+}
+
+/// Composites `layer` onto `background` with its top-left corner at `x`,
+/// `y`, blending colors with `mode` and scaling the layer's alpha by
+/// `opacity`.
+pub(crate) fn blend_layer(
+    background: &mut DynamicImage,
+    layer: &RgbaImage,
+    x: i64,
+    y: i64,
+    mode: BlendMode,
+    opacity: f32,
+) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    if opacity == 0.0 {
+        return;
+    }
+    let mut rgba = std::mem::take(background).into_rgba8();
+    let (width, height) = (rgba.width() as i64, rgba.height() as i64);
+
+    for (lx, ly, top) in layer.enumerate_pixels() {
+        let (bx, by) = (x + lx as i64, y + ly as i64);
+        if top[3] == 0 || bx < 0 || by < 0 || bx >= width || by >= height {
+            continue;
+        }
+        let bottom = rgba.get_pixel_mut(bx as u32, by as u32);
+        let top_alpha = top[3] as f32 / 255.0 * opacity;
+        let bottom_alpha = bottom[3] as f32 / 255.0;
+        let alpha = top_alpha + bottom_alpha * (1.0 - top_alpha);
+        if alpha == 0.0 {
+            continue;
+        }
+        let b: [f32; 3] = std::array::from_fn(|c| bottom[c] as f32 / 255.0);
+        let t: [f32; 3] = std::array::from_fn(|c| top[c] as f32 / 255.0);
+        let blended = mode.blend(b, t);
+        for c in 0..3 {
+            // The blended color only shows where both layers are opaque
+            let mixed = (1.0 - bottom_alpha) * t[c] + bottom_alpha * blended[c];
+            let value = (top_alpha * mixed + (1.0 - top_alpha) * bottom_alpha * b[c]) / alpha;
+            bottom[c] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        bottom[3] = (alpha * 255.0).round() as u8;
+    }
+    *background = DynamicImage::ImageRgba8(rgba);
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn gray(v: f32) -> [f32; 3] {
+        [v; 3]
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(a, e)| (a - e).abs() < EPSILON),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    /// One channel of `mode` blending `top` onto `bottom`.
+    fn blend(mode: BlendMode, bottom: f32, top: f32) -> f32 {
+        mode.blend(gray(bottom), gray(top))[0]
+    }
+
+    #[test]
+    fn separable_modes_match_known_values() {
+        let cases = [
+            (BlendMode::Normal, 0.2, 0.7, 0.7),
+            (BlendMode::Multiply, 0.5, 0.5, 0.25),
+            (BlendMode::Multiply, 0.3, 1.0, 0.3),
+            (BlendMode::Screen, 0.5, 0.5, 0.75),
+            (BlendMode::Screen, 0.3, 0.0, 0.3),
+            // Overlay is hard light with the layers swapped
+            (BlendMode::Overlay, 0.25, 0.6, 0.3),
+            (BlendMode::Overlay, 0.75, 0.4, 0.7),
+            (BlendMode::HardLight, 0.6, 0.25, 0.3),
+            (BlendMode::SoftLight, 0.5, 0.25, 0.375),
+            (BlendMode::SoftLight, 0.25, 0.75, 0.375),
+            (BlendMode::SoftLight, 0.64, 1.0, 0.8),
+            (BlendMode::SoftLight, 0.3, 0.5, 0.3),
+            (BlendMode::ColorDodge, 0.25, 0.5, 0.5),
+            (BlendMode::ColorDodge, 0.6, 0.5, 1.0),
+            (BlendMode::ColorDodge, 0.0, 1.0, 0.0),
+            (BlendMode::ColorBurn, 0.75, 0.5, 0.5),
+            (BlendMode::ColorBurn, 1.0, 0.0, 1.0),
+            (BlendMode::Darken, 0.3, 0.6, 0.3),
+            (BlendMode::Lighten, 0.3, 0.6, 0.6),
+            (BlendMode::Difference, 0.3, 0.8, 0.5),
+            (BlendMode::Exclusion, 0.5, 0.5, 0.5),
+            (BlendMode::Add, 0.7, 0.6, 1.0),
+            (BlendMode::Subtract, 0.3, 0.6, 0.0),
+        ];
+        for (mode, bottom, top, expected) in cases {
+            let actual = blend(mode, bottom, top);
+            assert!(
+                (actual - expected).abs() < EPSILON,
+                "{mode:?}({bottom}, {top}) = {actual}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn non_separable_modes_match_known_values() {
+        let red = [1.0, 0.0, 0.0];
+        let pink = [0.8, 0.4, 0.4];
+        // Red lifted to luminosity 0.5 clips back into range
+        let light_red = [1.0, 2.0 / 7.0, 2.0 / 7.0];
+        assert_close(BlendMode::Luminosity.blend(red, gray(0.5)), light_red);
+        assert_close(BlendMode::Color.blend(gray(0.5), red), light_red);
+        assert_close(
+            BlendMode::Hue.blend(pink, [0.0, 0.0, 1.0]),
+            [0.476, 0.476, 0.876],
+        );
+        assert_close(BlendMode::Saturation.blend(pink, gray(0.3)), gray(0.52));
+        assert_close(BlendMode::Saturation.blend(gray(0.4), red), gray(0.4));
+    }
+
+    #[test]
+    fn non_separable_modes_keep_the_luminosity_they_take() {
+        let colors = [
+            [0.9, 0.2, 0.1],
+            [0.1, 0.7, 0.3],
+            [0.2, 0.3, 0.95],
+            gray(0.0),
+            gray(1.0),
+        ];
+        for bottom in colors {
+            for top in colors {
+                for (mode, source) in [
+                    (BlendMode::Hue, bottom),
+                    (BlendMode::Saturation, bottom),
+                    (BlendMode::Color, bottom),
+                    (BlendMode::Luminosity, top),
+                ] {
+                    let blended = mode.blend(bottom, top);
+                    assert!((lum(blended) - lum(source)).abs() < EPSILON, "{mode:?}");
+                    assert!(
+                        blended
+                            .iter()
+                            .all(|c| (-EPSILON..=1.0 + EPSILON).contains(c))
+                    );
+                }
+            }
+        }
+    }
+
+    fn composite(bottom: [u8; 4], top: [u8; 4], mode: BlendMode, opacity: f32) -> Rgba<u8> {
+        let mut background = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba(bottom)));
+        let layer = RgbaImage::from_pixel(1, 1, Rgba(top));
+        blend_layer(&mut background, &layer, 0, 0, mode, opacity);
+        *background.as_rgba8().unwrap().get_pixel(0, 0)
+    }
+
+    #[test]
+    fn composites_with_source_over_alpha() {
+        // Opaque layers replace, or blend with, what is beneath
+        assert_eq!(
+            composite(
+                [10, 20, 30, 255],
+                [200, 100, 50, 255],
+                BlendMode::Normal,
+                1.0
+            ),
+            Rgba([200, 100, 50, 255])
+        );
+        assert_eq!(
+            composite(
+                [128, 255, 0, 255],
+                [128, 128, 128, 255],
+                BlendMode::Multiply,
+                1.0
+            ),
+            Rgba([64, 128, 0, 255])
+        );
+        // Half opacity mixes halfway
+        assert_eq!(
+            composite([0, 0, 0, 255], [255, 255, 255, 255], BlendMode::Normal, 0.5),
+            Rgba([128, 128, 128, 255])
+        );
+        // Over transparency the layer shows unblended
+        assert_eq!(
+            composite([0, 0, 0, 0], [200, 100, 50, 128], BlendMode::Multiply, 1.0),
+            Rgba([200, 100, 50, 128])
+        );
+        // Both half transparent: alpha 0.75, color (0.5 * top + 0.25 * bottom) / 0.75
+        assert_eq!(
+            composite([0, 0, 0, 128], [255, 255, 255, 128], BlendMode::Normal, 1.0),
+            Rgba([170, 170, 170, 192])
+        );
+        // Nothing happens at zero opacity
+        assert_eq!(
+            composite([1, 2, 3, 4], [255, 255, 255, 255], BlendMode::Normal, 0.0),
+            Rgba([1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn places_the_layer_and_clips_it_to_the_background() {
+        let mut background = DynamicImage::ImageRgba8(RgbaImage::new(3, 3));
+        let layer = RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        blend_layer(&mut background, &layer, -1, 2, BlendMode::Normal, 1.0);
+        let rgba = background.as_rgba8().unwrap();
+        let covered: Vec<(u32, u32)> = rgba
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[3] > 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert_eq!(covered, [(0, 2)]);
+    }
+}
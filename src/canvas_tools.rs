@@ -0,0 +1,469 @@
+use eframe::egui::{self, Color32, Pos2, Rect, Sense, Stroke, Vec2};
+
+use crate::{
+    image_editor::{EffectType, WatermarkParams},
+    imageproc_util::watermark_text_size,
+    viewer::Viewer,
+};
+
+/// Side of a square handle, in points.
+const HANDLE_SIZE: f32 = 9.0;
+/// Distance of the rotation handle from the top edge, in points.
+const ROTATE_HANDLE_OFFSET: f32 = 28.0;
+/// Rotation steps while Shift is held, in degrees.
+const ROTATE_SNAP: f32 = 15.0;
+
+#[derive(Clone, Copy, PartialEq, Hash)]
+enum Handle {
+    Move,
+    Rotate,
+    /// Watermark corner, counted clockwise from the top left.
+    Scale(usize),
+    /// Crop edges that follow the pointer.
+    Resize {
+        left: bool,
+        top: bool,
+        right: bool,
+        bottom: bool,
+    },
+}
+
+struct Drag {
+    handle: Handle,
+    /// Pointer position where the drag started, in image pixels.
+    start_pointer: Pos2,
+    /// The effect as it was when the drag started.
+    start: EffectType,
+}
+
+/// On-canvas handles for editing an op's parameters directly on the preview.
+#[derive(Default)]
+pub(crate) struct CanvasTools {
+    drag: Option<Drag>,
+}
+
+impl CanvasTools {
+    /// Whether `effect` can be edited on the canvas.
+    pub(crate) fn supports(effect: &EffectType) -> bool {
+        matches!(
+            effect,
+            EffectType::Watermark { .. } | EffectType::Crop { .. }
+        )
+    }
+
+    /// Whether the preview should stop before the op rather than after it,
+    /// so the whole area it works on stays visible.
+    pub(crate) fn shows_input(effect: &EffectType) -> bool {
+        matches!(effect, EffectType::Crop { .. })
+    }
+
+    /// Draws the handles of `effect` over the image shown by `viewer` and
+    /// applies drags to it. `image_size` is the size of the op's input, in
+    /// image pixels. Returns whether `effect` changed.
+    pub(crate) fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        viewer: &Viewer,
+        effect: &mut EffectType,
+        image_size: Vec2,
+    ) -> bool {
+        if ui.input(|i| i.pointer.any_released()) {
+            self.drag = None;
+        }
+        match effect {
+            EffectType::Watermark { .. } => self.watermark(ui, viewer, effect, image_size),
+            EffectType::Crop { .. } => self.crop(ui, viewer, effect, image_size),
+            _ => false,
+        }
+    }
+
+    /// Registers a handle widget and starts or continues a drag on it.
+    /// Returns the pointer position in image pixels while this handle is
+    /// being dragged.
+    fn handle(
+        &mut self,
+        ui: &egui::Ui,
+        viewer: &Viewer,
+        image_size: Vec2,
+        rect: Rect,
+        handle: Handle,
+        effect: &EffectType,
+    ) -> Option<Pos2> {
+        let id = ui.id().with(("canvas_handle", handle));
+        let cursor = match handle {
+            Handle::Move => egui::CursorIcon::Move,
+            Handle::Rotate => egui::CursorIcon::Alias,
+            Handle::Scale(_) => egui::CursorIcon::Crosshair,
+            Handle::Resize {
+                left,
+                top,
+                right,
+                bottom,
+            } => match (left || right, top || bottom) {
+                (true, true) if left == top => egui::CursorIcon::ResizeNwSe,
+                (true, true) => egui::CursorIcon::ResizeNeSw,
+                (true, false) => egui::CursorIcon::ResizeHorizontal,
+                _ => egui::CursorIcon::ResizeVertical,
+            },
+        };
+        let response = ui.interact(rect, id, Sense::drag()).on_hover_cursor(cursor);
+        let pointer = response
+            .interact_pointer_pos()
+            .map(|pos| viewer.screen_to_image(image_size, pos))?;
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            self.drag = Some(Drag {
+                handle,
+                start_pointer: pointer,
+                start: effect.clone(),
+            });
+        }
+        let dragging = self.drag.as_ref().is_some_and(|drag| drag.handle == handle);
+        (dragging && response.dragged()).then_some(pointer)
+    }
+
+    fn watermark(
+        &mut self,
+        ui: &mut egui::Ui,
+        viewer: &Viewer,
+        effect: &mut EffectType,
+        image_size: Vec2,
+    ) -> bool {
+        let EffectType::Watermark { params } = &*effect else {
+            return false;
+        };
+        let Some((width, height)) = watermark_text_size(params, image_size.x as u32) else {
+            return false;
+        };
+        let frame = WatermarkFrame::new(params, image_size, Vec2::new(width, height));
+        let to_screen = |pos: Pos2| viewer.image_to_screen(image_size, pos);
+        let corners = frame.corners.map(to_screen);
+        let top_center = to_screen(frame.pivot);
+        let up = (top_center - corners[3].lerp(corners[2], 0.5)).normalized();
+        let rotate_handle = top_center
+            + if up.is_finite() {
+                up * ROTATE_HANDLE_OFFSET
+            } else {
+                -Vec2::Y * ROTATE_HANDLE_OFFSET
+            };
+
+        // The body only claims the pointer over the text, so dragging
+        // elsewhere still pans the view
+        let hover = ui.ctx().pointer_hover_pos();
+        let moving = self
+            .drag
+            .as_ref()
+            .is_some_and(|drag| drag.handle == Handle::Move);
+        let mut new_params = None;
+        if moving || hover.is_some_and(|pos| contains(&corners, pos)) {
+            let area = viewer.pane_rect(0);
+            if let Some(pointer) = self.handle(ui, viewer, image_size, area, Handle::Move, effect)
+                && let Some(drag) = &self.drag
+                && let EffectType::Watermark { params: start } = &drag.start
+            {
+                let delta = pointer - drag.start_pointer;
+                let [half_width, half_height] =
+                    [image_size.x, image_size.y].map(|v| (v / 2.0) as i32);
+                new_params = Some(WatermarkParams {
+                    x: (start.x + delta.x.round() as i32).clamp(-half_width, half_width),
+                    y: (start.y + delta.y.round() as i32).clamp(-half_height, half_height),
+                    ..params.clone()
+                });
+            }
+        }
+        for (i, corner) in corners.into_iter().enumerate() {
+            let rect = Rect::from_center_size(corner, Vec2::splat(HANDLE_SIZE));
+            if let Some(pointer) =
+                self.handle(ui, viewer, image_size, rect, Handle::Scale(i), effect)
+                && let Some(drag) = &self.drag
+                && let EffectType::Watermark { params: start } = &drag.start
+            {
+                let ratio = (pointer - frame.pivot).length()
+                    / (drag.start_pointer - frame.pivot).length().max(1.0);
+                new_params = Some(WatermarkParams {
+                    scale: (start.scale * ratio).clamp(1.0, 100.0),
+                    ..params.clone()
+                });
+            }
+        }
+        let rect = Rect::from_center_size(rotate_handle, Vec2::splat(HANDLE_SIZE + 2.0));
+        if let Some(pointer) = self.handle(ui, viewer, image_size, rect, Handle::Rotate, effect)
+            && let Some(drag) = &self.drag
+            && let EffectType::Watermark { params: start } = &drag.start
+        {
+            let angle = |pos: Pos2| (pos - frame.pivot).angle().to_degrees();
+            let mut degree = start.degree + angle(pointer) - angle(drag.start_pointer);
+            if ui.input(|i| i.modifiers.shift) {
+                degree = (degree / ROTATE_SNAP).round() * ROTATE_SNAP;
+            }
+            new_params = Some(WatermarkParams {
+                degree: (degree + 180.0).rem_euclid(360.0) - 180.0,
+                ..params.clone()
+            });
+        }
+
+        let painter = ui.painter_at(viewer.pane_rect(0));
+        let stroke = Stroke::new(1.0, Color32::WHITE);
+        painter.add(egui::Shape::closed_line(corners.to_vec(), stroke));
+        painter.line_segment([top_center, rotate_handle], stroke);
+        painter.circle(
+            rotate_handle,
+            HANDLE_SIZE / 2.0 + 1.0,
+            Color32::WHITE,
+            outline(),
+        );
+        for corner in corners {
+            paint_handle(&painter, corner);
+        }
+
+        match new_params {
+            Some(new_params) if new_params != *params => {
+                *effect = EffectType::Watermark { params: new_params };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn crop(
+        &mut self,
+        ui: &mut egui::Ui,
+        viewer: &Viewer,
+        effect: &mut EffectType,
+        image_size: Vec2,
+    ) -> bool {
+        let EffectType::Crop { aspect, .. } = *effect else {
+            return false;
+        };
+        let size = [image_size.x as u32, image_size.y as u32];
+        let [x, y, width, height] = effect.crop_rect(size);
+        let crop = Rect::from_min_size(
+            Pos2::new(x as f32, y as f32),
+            Vec2::new(width as f32, height as f32),
+        );
+        let screen = Rect::from_min_max(
+            viewer.image_to_screen(image_size, crop.min),
+            viewer.image_to_screen(image_size, crop.max),
+        );
+
+        let hover = ui.ctx().pointer_hover_pos();
+        let moving = self
+            .drag
+            .as_ref()
+            .is_some_and(|drag| drag.handle == Handle::Move);
+        let mut new_rect = None;
+        if moving || hover.is_some_and(|pos| screen.contains(pos)) {
+            let area = viewer.pane_rect(0);
+            if let Some(pointer) = self.handle(ui, viewer, image_size, area, Handle::Move, effect)
+                && let Some(drag) = &self.drag
+            {
+                let start = start_rect(&drag.start, size);
+                let delta = (pointer - drag.start_pointer).round();
+                let max = image_size - start.size();
+                let min = (start.min + delta).clamp(Pos2::ZERO, max.to_pos2());
+                new_rect = Some(Rect::from_min_size(min, start.size()));
+            }
+        }
+        for (x_side, y_side) in [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (1, 0),
+            (1, 1),
+            (0, 1),
+            (-1, 1),
+            (-1, 0),
+        ] {
+            let handle = Handle::Resize {
+                left: x_side < 0,
+                top: y_side < 0,
+                right: x_side > 0,
+                bottom: y_side > 0,
+            };
+            let center = Pos2::new(
+                screen.center().x + screen.width() / 2.0 * x_side as f32,
+                screen.center().y + screen.height() / 2.0 * y_side as f32,
+            );
+            let rect = Rect::from_center_size(center, Vec2::splat(HANDLE_SIZE));
+            if let Some(pointer) = self.handle(ui, viewer, image_size, rect, handle, effect)
+                && let Some(drag) = &self.drag
+            {
+                let start = start_rect(&drag.start, size);
+                let delta = (pointer - drag.start_pointer).round();
+                new_rect = Some(resize_crop(start, handle, delta, image_size, aspect));
+            }
+        }
+
+        let painter = ui.painter_at(viewer.pane_rect(0));
+        let shade = Color32::from_black_alpha(140);
+        let image = viewer.image_rect(image_size);
+        for outside in [
+            Rect::from_x_y_ranges(image.x_range(), image.top()..=screen.top()),
+            Rect::from_x_y_ranges(image.x_range(), screen.bottom()..=image.bottom()),
+            Rect::from_x_y_ranges(image.left()..=screen.left(), screen.y_range()),
+            Rect::from_x_y_ranges(screen.right()..=image.right(), screen.y_range()),
+        ] {
+            painter.rect_filled(outside, 0.0, shade);
+        }
+        let thirds = Stroke::new(1.0, Color32::WHITE.gamma_multiply(0.4));
+        for t in [1.0 / 3.0, 2.0 / 3.0] {
+            painter.vline(screen.left() + screen.width() * t, screen.y_range(), thirds);
+            painter.hline(screen.x_range(), screen.top() + screen.height() * t, thirds);
+        }
+        painter.rect_stroke(
+            screen,
+            0.0,
+            Stroke::new(1.0, Color32::WHITE),
+            egui::StrokeKind::Middle,
+        );
+        for corner in [
+            screen.left_top(),
+            screen.center_top(),
+            screen.right_top(),
+            screen.right_center(),
+            screen.right_bottom(),
+            screen.center_bottom(),
+            screen.left_bottom(),
+            screen.left_center(),
+        ] {
+            paint_handle(&painter, corner);
+        }
+
+        let Some(rect) = new_rect else {
+            return false;
+        };
+        let [x, y, width, height] = [rect.min.x, rect.min.y, rect.width(), rect.height()]
+            .map(|v| v.round().max(0.0) as u32);
+        let new_effect = EffectType::Crop {
+            x,
+            y,
+            width: width.max(1),
+            height: height.max(1),
+            aspect,
+        };
+        let changed = new_effect.crop_rect(size) != effect.crop_rect(size);
+        *effect = new_effect;
+        changed
+    }
+}
+
+/// The watermark's text block in image pixels, as `draw_watermark` places
+/// it: hanging below the image center, rotated about it and then offset.
+struct WatermarkFrame {
+    /// Top center of the text block, which it rotates about.
+    pivot: Pos2,
+    /// Top left, top right, bottom right, bottom left.
+    corners: [Pos2; 4],
+}
+
+impl WatermarkFrame {
+    fn new(params: &WatermarkParams, image_size: Vec2, text_size: Vec2) -> Self {
+        let center = Pos2::new(
+            (image_size.x as u32 / 2) as f32,
+            (image_size.y as u32 / 2) as f32,
+        );
+        let pivot = center + Vec2::new(params.x as f32, params.y as f32);
+        let rot = egui::emath::Rot2::from_angle(params.degree.to_radians());
+        let half = text_size.x / 2.0;
+        let corners = [
+            Vec2::new(-half, 0.0),
+            Vec2::new(half, 0.0),
+            Vec2::new(half, text_size.y),
+            Vec2::new(-half, text_size.y),
+        ]
+        .map(|corner| pivot + rot * corner);
+        Self { pivot, corners }
+    }
+}
+
+/// Whether `pos` lies inside the convex quad `corners`.
+fn contains(corners: &[Pos2; 4], pos: Pos2) -> bool {
+    let side = |a: Pos2, b: Pos2| (b - a).x * (pos - a).y - (b - a).y * (pos - a).x;
+    let sides = [0, 1, 2, 3].map(|i| side(corners[i], corners[(i + 1) % 4]));
+    sides.iter().all(|s| *s >= 0.0) || sides.iter().all(|s| *s <= 0.0)
+}
+
+fn start_rect(start: &EffectType, size: [u32; 2]) -> Rect {
+    let [x, y, width, height] = start.crop_rect(size).map(|v| v as f32);
+    Rect::from_min_size(Pos2::new(x, y), Vec2::new(width, height))
+}
+
+/// Moves the edges of `start` picked by `handle` by `delta`, keeping the
+/// width:height `aspect` if set and the rectangle inside the image.
+fn resize_crop(
+    start: Rect,
+    handle: Handle,
+    delta: Vec2,
+    image_size: Vec2,
+    aspect: Option<[u32; 2]>,
+) -> Rect {
+    let Handle::Resize {
+        left,
+        top,
+        right,
+        bottom,
+    } = handle
+    else {
+        return start;
+    };
+    let mut rect = start;
+    if left {
+        rect.min.x = (rect.min.x + delta.x).clamp(0.0, rect.max.x - 1.0);
+    }
+    if right {
+        rect.max.x = (rect.max.x + delta.x).clamp(rect.min.x + 1.0, image_size.x);
+    }
+    if top {
+        rect.min.y = (rect.min.y + delta.y).clamp(0.0, rect.max.y - 1.0);
+    }
+    if bottom {
+        rect.max.y = (rect.max.y + delta.y).clamp(rect.min.y + 1.0, image_size.y);
+    }
+    let Some([aspect_w, aspect_h]) = aspect else {
+        return rect;
+    };
+
+    // Grow the other dimension to match, away from the fixed edges, then
+    // shrink both until the rectangle fits
+    let ratio = aspect_w as f32 / aspect_h.max(1) as f32;
+    let mut size = rect.size();
+    if left || right {
+        size.y = size.x / ratio;
+    } else {
+        size.x = size.y * ratio;
+    }
+    let room = Vec2::new(
+        if left {
+            rect.max.x
+        } else {
+            image_size.x - rect.min.x
+        },
+        if top {
+            rect.max.y
+        } else {
+            image_size.y - rect.min.y
+        },
+    );
+    size *= (room.x / size.x).min(room.y / size.y).min(1.0);
+    let size = size.round().max(Vec2::splat(1.0));
+    let x = if left {
+        rect.max.x - size.x
+    } else {
+        rect.min.x
+    };
+    let y = if top { rect.max.y - size.y } else { rect.min.y };
+    Rect::from_min_size(Pos2::new(x, y), size)
+}
+
+fn outline() -> Stroke {
+    Stroke::new(1.0, Color32::from_black_alpha(160))
+}
+
+fn paint_handle(painter: &egui::Painter, center: Pos2) {
+    painter.rect(
+        Rect::from_center_size(center, Vec2::splat(HANDLE_SIZE)),
+        1.0,
+        Color32::WHITE,
+        outline(),
+        egui::StrokeKind::Middle,
+    );
+}
@@ -0,0 +1,171 @@
+use image::DynamicImage;
+
+/// Rec. 709 luma weights, applied to sRGB values.
+const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Maps every pixel's RGB through `f`, with channels as 0.0..=1.0. Alpha
+/// is left alone and the result is clamped.
+pub(crate) fn map_rgb(img: DynamicImage, f: impl Fn([f32; 3]) -> [f32; 3]) -> DynamicImage {
+    let mut rgba = img.into_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let rgb = [pixel[0], pixel[1], pixel[2]].map(|v| v as f32 / 255.0);
+        let out = f(rgb);
+        for c in 0..3 {
+            pixel[c] = (out[c].clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Maps every RGB channel value through `lut`, keeping alpha.
+pub(crate) fn apply_lut(img: DynamicImage, lut: &[u8; 256]) -> DynamicImage {
+    let mut rgba = img.into_rgba8();
+    for pixel in rgba.pixels_mut() {
+        for c in 0..3 {
+            pixel[c] = lut[pixel[c] as usize];
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Builds a lookup table from a curve over 0.0..=1.0.
+pub(crate) fn lut_from_fn(f: impl Fn(f32) -> f32) -> [u8; 256] {
+    std::array::from_fn(|i| (f(i as f32 / 255.0).clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+pub(crate) fn gamma_lut(gamma: f32) -> [u8; 256] {
+    let exponent = 1.0 / gamma.max(0.01);
+    lut_from_fn(|v| v.powf(exponent))
+}
+
+/// Scales linear light by `2^stops`, like changing the exposure time.
+pub(crate) fn exposure_lut(stops: f32) -> [u8; 256] {
+    let gain = stops.exp2();
+    lut_from_fn(|v| linear_to_srgb(srgb_to_linear(v) * gain))
+}
+
+/// Maps `black..=white` onto the full range, with `mid` as the gamma of
+/// the midtones (above 1.0 brightens them).
+pub(crate) fn levels_lut(black: u8, white: u8, mid: f32) -> [u8; 256] {
+    let black = black as f32 / 255.0;
+    let white = (white as f32 / 255.0).max(black + 1.0 / 255.0);
+    let exponent = 1.0 / mid.max(0.01);
+    lut_from_fn(|v| {
+        ((v - black) / (white - black))
+            .clamp(0.0, 1.0)
+            .powf(exponent)
+    })
+}
+
+pub(crate) fn invert_lut() -> [u8; 256] {
+    lut_from_fn(|v| 1.0 - v)
+}
+
+pub(crate) fn hue_shift(img: DynamicImage, degrees: f32) -> DynamicImage {
+    let shift = degrees / 360.0;
+    map_rgb(img, |rgb| {
+        let [h, s, l] = rgb_to_hsl(rgb);
+        hsl_to_rgb([(h + shift).rem_euclid(1.0), s, l])
+    })
+}
+
+/// Scales HSL saturation by `1 + saturation`. `vibrance` works the same
+/// way but weighted towards colors that are still muted.
+pub(crate) fn saturate(img: DynamicImage, saturation: f32, vibrance: f32) -> DynamicImage {
+    map_rgb(img, |rgb| {
+        let [h, s, l] = rgb_to_hsl(rgb);
+        let s = s * (1.0 + saturation);
+        let s = s * (1.0 + vibrance * (1.0 - s.min(1.0)));
+        hsl_to_rgb([h, s.clamp(0.0, 1.0), l])
+    })
+}
+
+/// Warms (positive `temperature`) or cools the image and shifts it towards
+/// magenta (positive `tint`) or green. Both range over -1.0..=1.0.
+pub(crate) fn white_balance(img: DynamicImage, temperature: f32, tint: f32) -> DynamicImage {
+    let gains = [
+        1.0 + 0.3 * temperature,
+        1.0 - 0.3 * tint,
+        1.0 - 0.3 * temperature,
+    ];
+    map_rgb(img, |rgb| {
+        let mut out = [0.0; 3];
+        for c in 0..3 {
+            out[c] = linear_to_srgb(srgb_to_linear(rgb[c]) * gains[c]);
+        }
+        out
+    })
+}
+
+pub(crate) fn grayscale(img: DynamicImage) -> DynamicImage {
+    map_rgb(img, |rgb| [luma(rgb); 3])
+}
+
+/// Blends towards a sepia toned copy by `amount` (0.0..=1.0).
+pub(crate) fn sepia(img: DynamicImage, amount: f32) -> DynamicImage {
+    map_rgb(img, |[r, g, b]| {
+        let toned = [
+            0.393 * r + 0.769 * g + 0.189 * b,
+            0.349 * r + 0.686 * g + 0.168 * b,
+            0.272 * r + 0.534 * g + 0.131 * b,
+        ];
+        let rgb = [r, g, b];
+        std::array::from_fn(|c| rgb[c] + (toned[c] - rgb[c]) * amount)
+    })
+}
+
+pub(crate) fn luma(rgb: [f32; 3]) -> f32 {
+    rgb[0] * LUMA[0] + rgb[1] * LUMA[1] + rgb[2] * LUMA[2]
+}
+
+pub(crate) fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub(crate) fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Hue, saturation and lightness, all as 0.0..=1.0.
+fn rgb_to_hsl([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+    if d == 0.0 {
+        return [0.0, 0.0, l];
+    }
+    let s = d / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        ((g - b) / d).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    };
+    [h / 6.0, s, l]
+}
+
+fn hsl_to_rgb([h, s, l]: [f32; 3]) -> [f32; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let h = h * 6.0;
+    let x = c * (1.0 - (h.rem_euclid(2.0) - 1.0).abs());
+    let [r, g, b] = match h as u32 {
+        0 => [c, x, 0.0],
+        1 => [x, c, 0.0],
+        2 => [0.0, c, x],
+        3 => [0.0, x, c],
+        4 => [x, 0.0, c],
+        _ => [c, 0.0, x],
+    };
+    let m = l - c / 2.0;
+    [r + m, g + m, b + m]
+}
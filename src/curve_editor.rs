@@ -0,0 +1,191 @@
+use eframe::egui::{self, Color32, Pos2, Rect, Sense, Stroke, Vec2};
+
+use crate::{
+    curve_util::{CurveChannel, CurvesParams, MonotoneSpline},
+    histogram::{Histogram, LUMA_CHANNEL},
+    histogram_view::paint_channel,
+};
+
+/// Pointer distance in points within which a control point is picked.
+const PICK_RADIUS: f32 = 8.0;
+/// Closest two control points may get along the input axis.
+const MIN_GAP: f32 = 0.01;
+
+/// Channel picker plus an editable graph of that channel's curve, with
+/// `histogram` (of the effect's input) drawn behind it.
+///
+/// Click or drag on empty space to add a point, drag points to move them,
+/// and double- or right-click a point to delete it. Returns whether the
+/// curves changed.
+pub(crate) fn curves_editor(
+    ui: &mut egui::Ui,
+    curves: &mut CurvesParams,
+    histogram: Option<&Histogram>,
+) -> bool {
+    let channel_id = ui.id().with("curve_channel");
+    let mut channel: CurveChannel = ui.data(|d| d.get_temp(channel_id).unwrap_or_default());
+    let mut changed = false;
+
+    ui.vertical(|ui| {
+        ui.horizontal(|ui| {
+            ui.label("Curves");
+            for option in CurveChannel::ALL {
+                ui.selectable_value(&mut channel, option, option.name());
+            }
+            if ui
+                .small_button("Reset")
+                .on_hover_text("Reset this channel")
+                .clicked()
+            {
+                *curves.points_mut(channel) = CurvesParams::default().points(channel).clone();
+                changed = true;
+            }
+        });
+        ui.data_mut(|d| d.insert_temp(channel_id, channel));
+
+        let side = ui.available_width().clamp(120.0, 240.0);
+        let (rect, response) = ui.allocate_exact_size(Vec2::splat(side), Sense::click_and_drag());
+        let points = curves.points_mut(channel);
+        changed |= edit_points(ui, rect, &response, points);
+        paint_graph(ui, rect, channel, points, histogram);
+    });
+    changed
+}
+
+fn to_screen(rect: Rect, [x, y]: [f32; 2]) -> Pos2 {
+    Pos2::new(
+        rect.left() + x * rect.width(),
+        rect.bottom() - y * rect.height(),
+    )
+}
+
+fn to_curve(rect: Rect, pos: Pos2) -> [f32; 2] {
+    [
+        ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+        ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+    ]
+}
+
+fn edit_points(
+    ui: &egui::Ui,
+    rect: Rect,
+    response: &egui::Response,
+    points: &mut Vec<[f32; 2]>,
+) -> bool {
+    let drag_id = response.id.with("dragged_point");
+    let nearest = |points: &[[f32; 2]], pos: Pos2| {
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i, to_screen(rect, *p).distance(pos)))
+            .filter(|(_, distance)| *distance <= PICK_RADIUS)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    };
+    let Some(pointer) = response.interact_pointer_pos().or(response.hover_pos()) else {
+        return false;
+    };
+
+    let delete = response.double_clicked() || response.secondary_clicked();
+    if delete && points.len() > 2 {
+        if let Some(i) = nearest(points, pointer) {
+            points.remove(i);
+            return true;
+        }
+        return false;
+    }
+
+    if response.drag_started_by(egui::PointerButton::Primary)
+        || response.clicked_by(egui::PointerButton::Primary)
+    {
+        // Pick by where the press started, the pointer may have moved since
+        let origin = ui.input(|i| i.pointer.press_origin()).unwrap_or(pointer);
+        let index = nearest(points, origin).unwrap_or_else(|| insert_point(points, rect, origin));
+        ui.data_mut(|d| d.insert_temp(drag_id, index));
+    }
+    let Some(index) = ui.data(|d| d.get_temp::<usize>(drag_id)) else {
+        return false;
+    };
+    if !response.is_pointer_button_down_on() {
+        ui.data_mut(|d| d.remove::<usize>(drag_id));
+    }
+    if index >= points.len() {
+        return false;
+    }
+
+    // Keep the points in order by stopping at the neighbours
+    let [mut x, y] = to_curve(rect, pointer);
+    let low = index.checked_sub(1).map_or(0.0, |i| points[i][0] + MIN_GAP);
+    let high = points.get(index + 1).map_or(1.0, |p| p[0] - MIN_GAP);
+    x = x.clamp(low.min(high), high.max(low));
+    let moved = points[index] != [x, y];
+    points[index] = [x, y];
+    moved
+}
+
+/// Adds a point at `pos` in sorted position and returns its index.
+fn insert_point(points: &mut Vec<[f32; 2]>, rect: Rect, pos: Pos2) -> usize {
+    let point = to_curve(rect, pos);
+    let index = points.partition_point(|p| p[0] < point[0]);
+    points.insert(index, point);
+    index
+}
+
+fn paint_graph(
+    ui: &egui::Ui,
+    rect: Rect,
+    channel: CurveChannel,
+    points: &[[f32; 2]],
+    histogram: Option<&Histogram>,
+) {
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+    let color = match channel {
+        CurveChannel::Rgb | CurveChannel::Luma => visuals.strong_text_color(),
+        CurveChannel::Red => Color32::from_rgb(230, 70, 70),
+        CurveChannel::Green => Color32::from_rgb(70, 200, 70),
+        CurveChannel::Blue => Color32::from_rgb(80, 120, 240),
+    };
+
+    if let Some(histogram) = histogram {
+        let counts = match channel {
+            CurveChannel::Rgb | CurveChannel::Luma => &histogram.channels[LUMA_CHANNEL],
+            CurveChannel::Red => &histogram.channels[0],
+            CurveChannel::Green => &histogram.channels[1],
+            CurveChannel::Blue => &histogram.channels[2],
+        };
+        paint_channel(&painter, rect, counts, color.gamma_multiply(0.25));
+    }
+
+    let grid = Stroke::new(1.0, visuals.weak_text_color().gamma_multiply(0.4));
+    for t in [0.25, 0.5, 0.75] {
+        painter.vline(rect.left() + rect.width() * t, rect.y_range(), grid);
+        painter.hline(rect.x_range(), rect.top() + rect.height() * t, grid);
+    }
+    painter.line_segment([rect.left_bottom(), rect.right_top()], grid);
+
+    let spline = MonotoneSpline::new(points);
+    let curve: Vec<Pos2> = (0..=128)
+        .map(|i| {
+            let x = i as f32 / 128.0;
+            to_screen(rect, [x, spline.eval(x).clamp(0.0, 1.0)])
+        })
+        .collect();
+    painter.add(egui::Shape::line(curve, Stroke::new(1.5, color)));
+    for point in points {
+        painter.circle(
+            to_screen(rect, *point),
+            4.0,
+            color,
+            Stroke::new(1.0, visuals.extreme_bg_color),
+        );
+    }
+    painter.rect_stroke(
+        rect,
+        2.0,
+        visuals.widgets.noninteractive.bg_stroke,
+        egui::StrokeKind::Inside,
+    );
+}
@@ -0,0 +1,271 @@
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::color_util::{luma, lut_from_fn, map_rgb};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum CurveChannel {
+    #[default]
+    Rgb,
+    Red,
+    Green,
+    Blue,
+    Luma,
+}
+
+impl CurveChannel {
+    pub(crate) const ALL: [CurveChannel; 5] = [
+        CurveChannel::Rgb,
+        CurveChannel::Red,
+        CurveChannel::Green,
+        CurveChannel::Blue,
+        CurveChannel::Luma,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            CurveChannel::Rgb => "RGB",
+            CurveChannel::Red => "R",
+            CurveChannel::Green => "G",
+            CurveChannel::Blue => "B",
+            CurveChannel::Luma => "Luma",
+        }
+    }
+}
+
+/// Control points of one curve as `[input, output]` pairs in 0.0..=1.0,
+/// sorted by input.
+pub(crate) type CurvePoints = Vec<[f32; 2]>;
+
+fn identity() -> CurvePoints {
+    vec![[0.0, 0.0], [1.0, 1.0]]
+}
+
+/// One curve per channel. The red, green and blue curves apply first, then
+/// the RGB curve to all three, then the luma curve to the brightness alone.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CurvesParams {
+    pub(crate) rgb: CurvePoints,
+    pub(crate) red: CurvePoints,
+    pub(crate) green: CurvePoints,
+    pub(crate) blue: CurvePoints,
+    pub(crate) luma: CurvePoints,
+}
+
+impl Default for CurvesParams {
+    fn default() -> Self {
+        Self {
+            rgb: identity(),
+            red: identity(),
+            green: identity(),
+            blue: identity(),
+            luma: identity(),
+        }
+    }
+}
+
+impl CurvesParams {
+    pub(crate) fn points(&self, channel: CurveChannel) -> &CurvePoints {
+        match channel {
+            CurveChannel::Rgb => &self.rgb,
+            CurveChannel::Red => &self.red,
+            CurveChannel::Green => &self.green,
+            CurveChannel::Blue => &self.blue,
+            CurveChannel::Luma => &self.luma,
+        }
+    }
+
+    pub(crate) fn points_mut(&mut self, channel: CurveChannel) -> &mut CurvePoints {
+        match channel {
+            CurveChannel::Rgb => &mut self.rgb,
+            CurveChannel::Red => &mut self.red,
+            CurveChannel::Green => &mut self.green,
+            CurveChannel::Blue => &mut self.blue,
+            CurveChannel::Luma => &mut self.luma,
+        }
+    }
+
+    pub(crate) fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let master = curve_lut(&self.rgb);
+        let channels = [&self.red, &self.green, &self.blue].map(|points| curve_lut(points));
+        // Fold the master curve into the per-channel ones
+        let luts: [[u8; 256]; 3] = channels.map(|lut| lut.map(|v| master[v as usize]));
+
+        let mut rgba = img.into_rgba8();
+        for pixel in rgba.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = luts[c][pixel[c] as usize];
+            }
+        }
+        let img = DynamicImage::ImageRgba8(rgba);
+
+        if is_identity(&self.luma) {
+            return img;
+        }
+        let spline = MonotoneSpline::new(&self.luma);
+        map_rgb(img, |rgb| {
+            let shift = spline.eval(luma(rgb)) - luma(rgb);
+            rgb.map(|v| v + shift)
+        })
+    }
+}
+
+fn is_identity(points: &[[f32; 2]]) -> bool {
+    points.iter().all(|[x, y]| x == y)
+}
+
+/// Samples the curve through `points` into a lookup table.
+pub(crate) fn curve_lut(points: &[[f32; 2]]) -> [u8; 256] {
+    let spline = MonotoneSpline::new(points);
+    lut_from_fn(|v| spline.eval(v))
+}
+
+/// Piecewise cubic Hermite interpolation with Fritsch-Carlson tangents,
+/// which never overshoots between control points. Flat outside them.
+pub(crate) struct MonotoneSpline {
+    points: Vec<[f32; 2]>,
+    tangents: Vec<f32>,
+}
+
+impl MonotoneSpline {
+    pub(crate) fn new(points: &[[f32; 2]]) -> Self {
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        points.dedup_by(|a, b| a[0] == b[0]);
+
+        let n = points.len();
+        let secants: Vec<f32> = points
+            .windows(2)
+            .map(|w| (w[1][1] - w[0][1]) / (w[1][0] - w[0][0]))
+            .collect();
+        let mut tangents = vec![0.0; n];
+        if n >= 2 {
+            tangents[0] = secants[0];
+            tangents[n - 1] = secants[n - 2];
+            for k in 1..n - 1 {
+                let (before, after) = (secants[k - 1], secants[k]);
+                tangents[k] = if before * after <= 0.0 {
+                    0.0
+                } else {
+                    (before + after) / 2.0
+                };
+            }
+            for (k, &secant) in secants.iter().enumerate() {
+                if secant == 0.0 {
+                    tangents[k] = 0.0;
+                    tangents[k + 1] = 0.0;
+                    continue;
+                }
+                let a = tangents[k] / secant;
+                let b = tangents[k + 1] / secant;
+                let s = a * a + b * b;
+                if s > 9.0 {
+                    let t = 3.0 / s.sqrt();
+                    tangents[k] = t * a * secant;
+                    tangents[k + 1] = t * b * secant;
+                }
+            }
+        }
+        Self { points, tangents }
+    }
+
+    pub(crate) fn eval(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return x;
+        };
+        if x <= first[0] {
+            return first[1];
+        }
+        if x >= last[0] {
+            return last[1];
+        }
+        let k = points.partition_point(|p| p[0] <= x) - 1;
+        let ([x0, y0], [x1, y1]) = (points[k], points[k + 1]);
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * h * self.tangents[k]
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * h * self.tangents[k + 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    /// The spline sampled at 1001 evenly spaced inputs over 0.0..=1.0.
+    fn samples(spline: &MonotoneSpline) -> Vec<f32> {
+        (0..=1000).map(|i| spline.eval(i as f32 / 1000.0)).collect()
+    }
+
+    #[test]
+    fn passes_through_the_control_points() {
+        let points = [[0.7, 0.9], [0.0, 0.1], [0.3, 0.2], [1.0, 0.95]];
+        let spline = MonotoneSpline::new(&points);
+        for [x, y] in points {
+            assert!((spline.eval(x) - y).abs() < EPSILON, "at {x}");
+        }
+    }
+
+    #[test]
+    fn does_not_overshoot_rising_points() {
+        // Steep then nearly flat, where plain cubic splines overshoot
+        let points = [[0.0, 0.0], [0.1, 0.8], [0.2, 0.85], [0.6, 0.86], [1.0, 1.0]];
+        let values = samples(&MonotoneSpline::new(&points));
+        assert!(values.windows(2).all(|w| w[1] >= w[0] - EPSILON));
+        assert!(values.iter().all(|v| (0.0..=1.0).contains(v)));
+    }
+
+    #[test]
+    fn does_not_overshoot_falling_points() {
+        let points = [[0.0, 1.0], [0.5, 0.9], [0.55, 0.1], [1.0, 0.0]];
+        let values = samples(&MonotoneSpline::new(&points));
+        assert!(values.windows(2).all(|w| w[1] <= w[0] + EPSILON));
+        assert!(values.iter().all(|v| (0.0..=1.0).contains(v)));
+    }
+
+    #[test]
+    fn keeps_equal_neighbours_flat() {
+        let spline = MonotoneSpline::new(&[[0.0, 0.0], [0.4, 0.5], [0.6, 0.5], [1.0, 1.0]]);
+        for i in 0..=20 {
+            let x = 0.4 + 0.2 * i as f32 / 20.0;
+            assert!((spline.eval(x) - 0.5).abs() < EPSILON, "at {x}");
+        }
+    }
+
+    #[test]
+    fn is_flat_outside_the_points() {
+        let spline = MonotoneSpline::new(&[[0.2, 0.3], [0.8, 0.6]]);
+        assert_eq!(spline.eval(0.0), 0.3);
+        assert_eq!(spline.eval(-1.0), 0.3);
+        assert_eq!(spline.eval(1.0), 0.6);
+    }
+
+    #[test]
+    fn handles_degenerate_points() {
+        // No points: the identity
+        let spline = MonotoneSpline::new(&[]);
+        assert_eq!(spline.eval(0.25), 0.25);
+        // One point: a constant
+        let spline = MonotoneSpline::new(&[[0.5, 0.7]]);
+        assert!(samples(&spline).iter().all(|v| *v == 0.7));
+        // Duplicate inputs: the first one given wins, without dividing by zero
+        let spline = MonotoneSpline::new(&[[0.0, 0.0], [0.5, 0.2], [0.5, 0.9], [1.0, 1.0]]);
+        assert!(samples(&spline).iter().all(|v| v.is_finite()));
+        assert!((spline.eval(0.5) - 0.2).abs() < EPSILON);
+        let spline = MonotoneSpline::new(&[[0.3, 0.4], [0.3, 0.6]]);
+        assert!(samples(&spline).iter().all(|v| *v == 0.4));
+    }
+
+    #[test]
+    fn identity_curve_gives_an_identity_table() {
+        let lut = curve_lut(&identity());
+        assert!(lut.iter().enumerate().all(|(i, v)| *v as usize == i));
+    }
+}
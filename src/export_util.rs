@@ -0,0 +1,179 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, Seek, Write},
+    path::Path,
+};
+
+use eframe::egui::Color32;
+use image::{
+    DynamicImage, ExtendedColorType, ImageEncoder, ImageResult, Rgb, RgbImage, RgbaImage,
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+        tiff::TiffEncoder,
+        webp::WebPEncoder,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Tiff,
+}
+
+impl ExportFormat {
+    pub(crate) const ALL: [ExportFormat; 4] = [
+        ExportFormat::Png,
+        ExportFormat::Jpeg,
+        ExportFormat::WebP,
+        ExportFormat::Tiff,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG",
+            ExportFormat::Jpeg => "JPEG",
+            ExportFormat::WebP => "WebP",
+            ExportFormat::Tiff => "TIFF",
+        }
+    }
+
+    /// File extensions for this format, the preferred one first.
+    pub(crate) fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ExportFormat::Png => &["png"],
+            ExportFormat::Jpeg => &["jpg", "jpeg"],
+            ExportFormat::WebP => &["webp"],
+            ExportFormat::Tiff => &["tif", "tiff"],
+        }
+    }
+
+    pub(crate) fn from_extension(ext: &str) -> Option<Self> {
+        let ext = ext.to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extensions().contains(&ext.as_str()))
+    }
+
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+
+    pub(crate) fn supports_alpha(self) -> bool {
+        !matches!(self, ExportFormat::Jpeg)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ExportOptions {
+    pub format: ExportFormat,
+    /// 1 to 100, only used for JPEG.
+    pub jpeg_quality: u8,
+    /// 0 (uncompressed) to 9 (smallest), only used for PNG.
+    pub png_compression: u8,
+    /// Composite the image over `background` and drop the alpha channel.
+    /// Always done for formats without alpha support.
+    pub flatten_alpha: bool,
+    pub background: Color32,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::Png,
+            jpeg_quality: 90,
+            png_compression: 6,
+            flatten_alpha: false,
+            background: Color32::WHITE,
+        }
+    }
+}
+
+/// Encodes `img` into `path`. The file is written next to it under a
+/// temporary name and only renamed into place once encoding succeeded, so a
+/// failed export never leaves a truncated file behind.
+pub(crate) fn export_image(
+    img: &DynamicImage,
+    path: &Path,
+    options: &ExportOptions,
+) -> ImageResult<()> {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".part");
+    let temp = path.with_file_name(name);
+
+    let result = write_image(img, &temp, options).and_then(|()| Ok(std::fs::rename(&temp, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_image(img: &DynamicImage, path: &Path, options: &ExportOptions) -> ImageResult<()> {
+    let rgba = img.to_rgba8();
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    if options.flatten_alpha || !options.format.supports_alpha() {
+        let rgb = flatten_alpha(&rgba, options.background);
+        encode(&mut writer, &rgb, ExtendedColorType::Rgb8, options)?;
+    } else {
+        encode(&mut writer, &rgba, ExtendedColorType::Rgba8, options)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn encode<W, P>(
+    writer: W,
+    img: &image::ImageBuffer<P, Vec<u8>>,
+    color: ExtendedColorType,
+    options: &ExportOptions,
+) -> ImageResult<()>
+where
+    W: Write + Seek,
+    P: image::Pixel<Subpixel = u8>,
+{
+    let (width, height) = img.dimensions();
+    match options.format {
+        ExportFormat::Png => {
+            let compression = match options.png_compression {
+                0 => CompressionType::Uncompressed,
+                level => CompressionType::Level(level.min(9)),
+            };
+            PngEncoder::new_with_quality(writer, compression, FilterType::Adaptive).write_image(
+                img.as_raw(),
+                width,
+                height,
+                color,
+            )
+        }
+        ExportFormat::Jpeg => JpegEncoder::new_with_quality(
+            writer,
+            options.jpeg_quality.clamp(1, 100),
+        )
+        .write_image(img.as_raw(), width, height, color),
+        // `image` only ships a lossless WebP encoder
+        ExportFormat::WebP => {
+            WebPEncoder::new_lossless(writer).write_image(img.as_raw(), width, height, color)
+        }
+        ExportFormat::Tiff => {
+            TiffEncoder::new(writer).write_image(img.as_raw(), width, height, color)
+        }
+    }
+}
+
+fn flatten_alpha(img: &RgbaImage, background: Color32) -> RgbImage {
+    let [bg_r, bg_g, bg_b, _] = background.to_array();
+    let bg = [bg_r as f32, bg_g as f32, bg_b as f32];
+
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let px = img.get_pixel(x, y);
+        let alpha = px[3] as f32 / 255.0;
+        let mix = |i: usize| (px[i] as f32 * alpha + bg[i] * (1.0 - alpha)).round() as u8;
+        Rgb([mix(0), mix(1), mix(2)])
+    })
+}
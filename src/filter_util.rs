@@ -0,0 +1,151 @@
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage};
+use imageproc::{
+    edges::canny,
+    filter::{
+        bilateral::GaussianEuclideanColorDistance, bilateral_filter, filter_clamped_parallel,
+        gaussian_blur_f32, median_filter,
+    },
+    gradients::sobel_gradients,
+    kernel::Kernel,
+};
+use serde::{Deserialize, Serialize};
+
+const SHARPEN_3X3: [i32; 9] = [0, -1, 0, -1, 5, -1, 0, -1, 0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) enum EdgeMethod {
+    #[default]
+    Sobel,
+    Canny,
+}
+
+impl EdgeMethod {
+    pub(crate) const ALL: [EdgeMethod; 2] = [EdgeMethod::Sobel, EdgeMethod::Canny];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            EdgeMethod::Sobel => "Sobel",
+            EdgeMethod::Canny => "Canny",
+        }
+    }
+}
+
+/// Copies the alpha of `original` into `filtered`, so filters only change
+/// the color.
+fn keep_alpha(mut filtered: RgbaImage, original: &RgbaImage) -> DynamicImage {
+    for (pixel, source) in filtered.pixels_mut().zip(original.pixels()) {
+        pixel[3] = source[3];
+    }
+    DynamicImage::ImageRgba8(filtered)
+}
+
+/// Adds `amount` times the difference to a Gaussian blur of `radius`,
+/// skipping differences below `threshold`.
+pub(crate) fn unsharp_mask(
+    img: DynamicImage,
+    amount: f32,
+    radius: f32,
+    threshold: u8,
+) -> DynamicImage {
+    if radius <= 0.0 || amount == 0.0 {
+        return img;
+    }
+    let mut rgba = img.into_rgba8();
+    let blurred = gaussian_blur_f32(&rgba, radius);
+    for (pixel, blur) in rgba.pixels_mut().zip(blurred.pixels()) {
+        for c in 0..3 {
+            let diff = pixel[c] as f32 - blur[c] as f32;
+            if diff.abs() >= threshold as f32 {
+                pixel[c] = (pixel[c] as f32 + amount * diff).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Identity minus the 3x3 Laplacian.
+pub(crate) fn sharpen3x3(img: DynamicImage) -> DynamicImage {
+    let rgba = img.into_rgba8();
+    let kernel = Kernel::new(&SHARPEN_3X3, 3, 3);
+    let sharpened: RgbaImage = filter_clamped_parallel::<_, i32, u8>(&rgba, kernel);
+    keep_alpha(sharpened, &rgba)
+}
+
+pub(crate) fn median(img: DynamicImage, radius: u32) -> DynamicImage {
+    if radius == 0 {
+        return img;
+    }
+    DynamicImage::ImageRgba8(median_filter(&img.into_rgba8(), radius, radius))
+}
+
+/// Edge-preserving smoothing: `spatial_sigma` is in pixels, `color_sigma`
+/// in 0..=255 channel units.
+pub(crate) fn bilateral(
+    img: DynamicImage,
+    radius: u8,
+    spatial_sigma: f32,
+    color_sigma: f32,
+) -> DynamicImage {
+    if radius == 0 {
+        return img;
+    }
+    let rgba = img.into_rgba8();
+    let filtered: RgbaImage = bilateral_filter(
+        &rgba,
+        radius,
+        spatial_sigma.max(0.1),
+        GaussianEuclideanColorDistance::new(color_sigma.max(0.1)),
+    );
+    keep_alpha(filtered, &rgba)
+}
+
+/// White edges on black. `low` and `high` are the Canny hysteresis
+/// thresholds and unused by Sobel.
+pub(crate) fn edges(img: DynamicImage, method: EdgeMethod, low: f32, high: f32) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let gray = img.into_luma8();
+    let edges: GrayImage = match method {
+        EdgeMethod::Sobel => {
+            let gradients = sobel_gradients(&gray);
+            GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+                Luma([gradients.get_pixel(x, y)[0].min(255) as u8])
+            })
+        }
+        EdgeMethod::Canny => canny(&gray, low, high.max(low)),
+    };
+    let colored = RgbaImage::from_fn(edges.width(), edges.height(), |x, y| {
+        let v = edges.get_pixel(x, y)[0];
+        Rgba([v, v, v, 255])
+    });
+    keep_alpha(colored, &rgba)
+}
+
+/// A `size`x`size` kernel that leaves the image unchanged.
+pub(crate) fn identity_kernel(size: u32) -> Vec<f32> {
+    let mut kernel = vec![0.0; (size * size) as usize];
+    kernel[(size * size / 2) as usize] = 1.0;
+    kernel
+}
+
+/// Correlates the image with the row-major `size`x`size` `kernel`, divided
+/// by the sum of its weights when `normalize` is set and that sum isn't 0.
+pub(crate) fn convolve(
+    img: DynamicImage,
+    kernel: &[f32],
+    size: u32,
+    normalize: bool,
+) -> DynamicImage {
+    if size == 0 || kernel.len() != (size * size) as usize {
+        return img;
+    }
+    let sum: f32 = kernel.iter().sum();
+    let weights: Vec<f32> = if normalize && sum != 0.0 {
+        kernel.iter().map(|w| w / sum).collect()
+    } else {
+        kernel.to_vec()
+    };
+    let rgba = img.into_rgba8();
+    let filtered: RgbaImage =
+        filter_clamped_parallel::<_, f32, u8>(&rgba, Kernel::new(&weights, size, size));
+    keep_alpha(filtered, &rgba)
+}
@@ -0,0 +1,134 @@
+use eframe::egui;
+
+use crate::{
+    font_registry::{FONT_EXTENSIONS, FontRegistry, FontSource, WEIGHT_AXIS},
+    image_editor::WatermarkParams,
+};
+
+/// Family picker with a search box, weight slider and italic toggle, plus a
+/// button to load a font file into the registry and sliders for the axes of
+/// a variable font. Returns whether anything changed.
+pub(crate) fn font_picker(ui: &mut egui::Ui, params: &mut WatermarkParams) -> bool {
+    let WatermarkParams {
+        font_family: family,
+        font_path,
+        weight,
+        italic,
+        variations,
+        ..
+    } = params;
+    let search_id = ui.id().with("font_search");
+    let error_id = ui.id().with("font_error");
+    let mut changed = false;
+
+    ui.vertical(|ui| {
+        ui.horizontal(|ui| {
+            ui.label("Font");
+            egui::ComboBox::from_id_salt(ui.id().with("font_family"))
+                .selected_text(family.as_str())
+                .width(160.0)
+                .show_ui(ui, |ui| {
+                    // The system fonts are only looked for once they're wanted
+                    let ctx = ui.ctx().clone();
+                    FontRegistry::scan_system_fonts_in_background(move || ctx.request_repaint());
+                    let mut search: String = ui.data(|d| d.get_temp(search_id).unwrap_or_default());
+                    ui.add(egui::TextEdit::singleline(&mut search).hint_text("Search"))
+                        .request_focus();
+                    let needle = search.to_lowercase();
+                    if !FontRegistry::system_fonts_scanned() {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.weak("Looking for system fonts…");
+                        });
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(240.0)
+                        .show(ui, |ui| {
+                            let registry = FontRegistry::get();
+                            for name in registry.families() {
+                                if !name.to_lowercase().contains(&needle) {
+                                    continue;
+                                }
+                                if ui.selectable_label(family == name, name).clicked() {
+                                    *family = name.to_string();
+                                    *font_path = match &registry.find(name, *weight, *italic).source
+                                    {
+                                        FontSource::File(path) => Some(path.clone()),
+                                        FontSource::Bundled(_) => None,
+                                    };
+                                    variations.clear();
+                                    changed = true;
+                                }
+                            }
+                        });
+                    ui.data_mut(|d| d.insert_temp(search_id, search));
+                });
+            if ui.button("…").on_hover_text("Load a font file").clicked()
+                && let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Fonts", FONT_EXTENSIONS)
+                    .pick_file()
+            {
+                match FontRegistry::add_file(&path) {
+                    Ok(loaded) => {
+                        *family = loaded;
+                        *font_path = Some(path);
+                        variations.clear();
+                        changed = true;
+                        ui.data_mut(|d| d.remove::<String>(error_id));
+                    }
+                    Err(err) => ui.data_mut(|d| d.insert_temp(error_id, err.to_string())),
+                }
+            }
+        });
+        if let Some(error) = ui.data(|d| d.get_temp::<String>(error_id)) {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Weight");
+            if ui
+                .add(egui::Slider::new(weight, 100..=900).step_by(100.0))
+                .changed()
+            {
+                // Hand the weight axis back to the weight
+                variations.remove(WEIGHT_AXIS);
+                changed = true;
+            }
+            let has_italic = FontRegistry::get().has_italic(family);
+            changed |= ui
+                .add_enabled(has_italic, egui::Checkbox::new(italic, "Italic"))
+                .on_disabled_hover_text("This family has no italic face")
+                .changed();
+        });
+
+        let registry = FontRegistry::get();
+        let face = registry.find(family, *weight, *italic);
+        for axis in &face.axes {
+            ui.horizontal(|ui| {
+                ui.label(&axis.name);
+                let mut value =
+                    variations
+                        .get(&axis.tag)
+                        .copied()
+                        .unwrap_or(if axis.tag == WEIGHT_AXIS {
+                            *weight as f32
+                        } else {
+                            axis.default
+                        });
+                if ui
+                    .add(egui::Slider::new(&mut value, axis.min..=axis.max))
+                    .changed()
+                {
+                    variations.insert(axis.tag.clone(), value);
+                    changed = true;
+                }
+                if variations.contains_key(&axis.tag)
+                    && ui.small_button("⟲").on_hover_text("Reset").clicked()
+                {
+                    variations.remove(&axis.tag);
+                    changed = true;
+                }
+            });
+        }
+    });
+    changed
+}
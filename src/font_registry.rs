@@ -0,0 +1,585 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock, Mutex, Once, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+};
+
+use ab_glyph::{FontVec, VariableFont};
+use serde::Serialize;
+use ttf_parser::{Face, RawFaceTables, fonts_in_collection, name_id};
+
+/// Fonts compiled into the binary, so text renders without any installed.
+const BUNDLED: [&[u8]; 2] = [
+    include_bytes!("../Roboto-VariableFont_wdth,wght.ttf"),
+    include_bytes!("../DejaVuSans.ttf"),
+];
+pub(crate) const DEFAULT_FAMILY: &str = "Roboto";
+pub(crate) const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc", "otc"];
+/// Tag of the weight axis, which `WatermarkParams::weight` sets.
+pub(crate) const WEIGHT_AXIS: &str = "wght";
+/// Weight preferred over a face of the wrong style.
+const STYLE_MISMATCH_PENALTY: u16 = 1000;
+/// Tables read to list a face: the mandatory ones plus its names, weight
+/// and style, and variation axes. The glyphs are only read when drawing.
+const LISTING_TABLES: [&[u8; 4]; 6] = [b"head", b"hhea", b"maxp", b"name", b"OS/2", b"fvar"];
+
+static REGISTRY: LazyLock<RwLock<FontRegistry>> =
+    LazyLock::new(|| RwLock::new(FontRegistry::new()));
+/// Guards the one scan of the system font directories.
+static SYSTEM_SCAN: Once = Once::new();
+/// Set once a background scan has been started.
+static SCAN_STARTED: AtomicBool = AtomicBool::new(false);
+/// Bumped whenever faces are added, see [`FontRegistry::generation`].
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Font files whose bytes are kept for reuse.
+const FONT_FILE_CACHE_SIZE: usize = 8;
+
+/// Font file bytes shared by every face and variation drawn from them,
+/// least recently used first.
+static FONT_FILES: LazyLock<Mutex<VecDeque<CachedFile>>> = LazyLock::new(Default::default);
+
+type CachedFile = (PathBuf, Arc<[u8]>);
+
+/// Identifies a parsed font: the face and the variation it is set to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct FontKey {
+    source: FontSource,
+    index: u32,
+    /// Axis tags and the bits of their coordinates.
+    coordinates: Vec<([u8; 4], u32)>,
+}
+
+/// Parsed fonts kept for reuse, each owning a copy of its file's bytes.
+const FONT_CACHE_SIZE: usize = 8;
+
+/// Parsed fonts set to their variation, shared by every render and
+/// measurement, least recently used first.
+static FONTS: LazyLock<Mutex<VecDeque<CachedFont>>> = LazyLock::new(Default::default);
+
+type CachedFont = (FontKey, Arc<FontVec>);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub(crate) enum FontSource {
+    /// Index into the bundled fonts.
+    Bundled(usize),
+    File(PathBuf),
+}
+
+/// One face of a font file, as listed in the registry.
+#[derive(Clone, Debug)]
+pub(crate) struct FontFace {
+    pub(crate) family: String,
+    /// CSS-style weight, 400 being regular and 700 bold.
+    pub(crate) weight: u16,
+    pub(crate) italic: bool,
+    /// Variation axes of a variable font, leaving out hidden ones.
+    pub(crate) axes: Vec<FontAxis>,
+    pub(crate) source: FontSource,
+    /// Index of the face within a font collection.
+    pub(crate) index: u32,
+}
+
+/// A design axis of a variable font, such as weight or width.
+#[derive(Clone, Debug)]
+pub(crate) struct FontAxis {
+    /// Four-letter tag, e.g. `wdth`.
+    pub(crate) tag: String,
+    pub(crate) name: String,
+    pub(crate) min: f32,
+    pub(crate) default: f32,
+    pub(crate) max: f32,
+}
+
+impl FontFace {
+    /// Whether the face has a `wght` axis, so any weight can be set on it.
+    pub(crate) fn variable_weight(&self) -> bool {
+        self.axes.iter().any(|axis| axis.tag == WEIGHT_AXIS)
+    }
+
+    /// The parsed face, set to `weight` if it has a weight axis and to the
+    /// axis coordinates in `variations`, by tag, which override the weight.
+    /// Parsed once per face and coordinates, then shared from the cache.
+    pub(crate) fn font(
+        &self,
+        weight: u16,
+        variations: &BTreeMap<String, f32>,
+    ) -> Result<Arc<FontVec>, Box<dyn Error>> {
+        let coordinates: Vec<([u8; 4], f32)> = self
+            .axes
+            .iter()
+            .filter_map(|axis| {
+                let value = variations
+                    .get(&axis.tag)
+                    .copied()
+                    .or_else(|| (axis.tag == WEIGHT_AXIS).then_some(weight as f32))?;
+                let tag = axis.tag.as_bytes().try_into().ok()?;
+                Some((tag, value.clamp(axis.min, axis.max)))
+            })
+            .collect();
+        let key = FontKey {
+            source: self.source.clone(),
+            index: self.index,
+            coordinates: coordinates
+                .iter()
+                .map(|(tag, value)| (*tag, value.to_bits()))
+                .collect(),
+        };
+        {
+            let mut fonts = FONTS.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(index) = fonts.iter().position(|(k, _)| *k == key) {
+                let entry = fonts.remove(index).expect("index is in range");
+                let font = entry.1.clone();
+                fonts.push_back(entry);
+                return Ok(font);
+            }
+        }
+        // Read and parse unlocked so text measuring isn't held up
+        let data = match &self.source {
+            FontSource::Bundled(i) => BUNDLED[*i].to_vec(),
+            FontSource::File(path) => file_data(path)?.to_vec(),
+        };
+        let mut font = FontVec::try_from_vec_and_index(data, self.index)?;
+        for (tag, value) in &coordinates {
+            font.set_variation(tag, *value);
+        }
+        let font = Arc::new(font);
+        let mut fonts = FONTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if fonts.len() >= FONT_CACHE_SIZE {
+            fonts.pop_front();
+        }
+        fonts.push_back((key, font.clone()));
+        Ok(font)
+    }
+}
+
+/// The bytes of the font file at `path`, read once and then shared from the
+/// cache by every variation parsed from it.
+fn file_data(path: &Path) -> io::Result<Arc<[u8]>> {
+    {
+        let mut files = FONT_FILES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = files.iter().position(|(p, _)| p == path) {
+            let entry = files.remove(index).expect("index is in range");
+            let data = entry.1.clone();
+            files.push_back(entry);
+            return Ok(data);
+        }
+    }
+    let data: Arc<[u8]> = std::fs::read(path)?.into();
+    let mut files = FONT_FILES.lock().unwrap_or_else(PoisonError::into_inner);
+    if files.len() >= FONT_FILE_CACHE_SIZE {
+        files.pop_front();
+    }
+    files.push_back((path.to_path_buf(), data.clone()));
+    Ok(data)
+}
+
+/// Fonts the watermark can be drawn with: the bundled ones plus any
+/// loaded from files or found in the system font directories.
+pub(crate) struct FontRegistry {
+    faces: Vec<FontFace>,
+    /// Font files that couldn't be loaded and why, so renders don't try
+    /// them again.
+    failed_files: HashMap<PathBuf, String>,
+    /// Failures not yet handed out by [`Self::take_file_errors`].
+    unreported: Vec<String>,
+}
+
+impl FontRegistry {
+    fn new() -> Self {
+        let faces = BUNDLED
+            .iter()
+            .enumerate()
+            .flat_map(|(i, data)| parse_faces(data, FontSource::Bundled(i)))
+            .collect();
+        Self {
+            faces,
+            failed_files: HashMap::new(),
+            unreported: vec![],
+        }
+    }
+
+    /// The shared registry. It starts out with the bundled fonts only, see
+    /// [`Self::scan_system_fonts`].
+    pub(crate) fn get() -> RwLockReadGuard<'static, FontRegistry> {
+        REGISTRY.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn get_mut() -> RwLockWriteGuard<'static, FontRegistry> {
+        REGISTRY.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds every face of the font file at `path` and returns the family of
+    /// the first. The file is read before taking the registry's lock.
+    pub(crate) fn add_file(path: &Path) -> Result<String, Box<dyn Error>> {
+        let faces = list_faces(path)?;
+        let family = faces
+            .first()
+            .map(|face| face.family.clone())
+            .ok_or_else(|| format!("{} has no readable font faces", path.display()))?;
+        let mut registry = Self::get_mut();
+        registry.insert(faces);
+        registry.failed_files.remove(path);
+        Ok(family)
+    }
+
+    /// Adds the font file at `path` unless that was done or failed before.
+    /// A failure is remembered, and handed out once by
+    /// [`Self::take_file_errors`].
+    pub(crate) fn require_file(path: &Path) -> Result<(), String> {
+        {
+            let registry = Self::get();
+            if let Some(err) = registry.failed_files.get(path) {
+                return Err(err.clone());
+            }
+            let source = FontSource::File(path.to_path_buf());
+            if registry.faces.iter().any(|f| f.source == source) {
+                return Ok(());
+            }
+        }
+        Self::add_file(path).map(|_| ()).map_err(|err| {
+            let err = format!("Could not load font {}: {err}", path.display());
+            let mut registry = Self::get_mut();
+            registry
+                .failed_files
+                .insert(path.to_path_buf(), err.clone());
+            registry.unreported.push(err.clone());
+            err
+        })
+    }
+
+    /// Font files that failed to load since the last call, see
+    /// [`Self::require_file`].
+    pub(crate) fn take_file_errors() -> Vec<String> {
+        if Self::get().unreported.is_empty() {
+            return vec![];
+        }
+        std::mem::take(&mut Self::get_mut().unreported)
+    }
+
+    fn insert(&mut self, faces: Vec<FontFace>) {
+        let count = self.faces.len();
+        for face in faces {
+            let known = self
+                .faces
+                .iter()
+                .any(|f| f.source == face.source && f.index == face.index);
+            if !known {
+                self.faces.push(face);
+            }
+        }
+        if self.faces.len() > count {
+            GENERATION.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Changes whenever faces are added, which can change the face a family
+    /// resolves to. Part of the cache keys of everything drawn with fonts.
+    pub(crate) fn generation() -> u64 {
+        GENERATION.load(Ordering::Relaxed)
+    }
+
+    /// Adds the fonts found in the usual system font directories, once.
+    /// Waits for a scan already running on another thread.
+    pub(crate) fn scan_system_fonts() {
+        SYSTEM_SCAN.call_once(|| {
+            let mut faces = vec![];
+            let mut visited = HashSet::new();
+            for dir in system_font_dirs() {
+                scan_dir(&dir, &mut visited, &mut faces);
+            }
+            Self::get_mut().insert(faces);
+        });
+    }
+
+    /// Runs [`Self::scan_system_fonts`] on a background thread and calls
+    /// `on_done` once the fonts are in. Does nothing if a background scan
+    /// was started before.
+    pub(crate) fn scan_system_fonts_in_background(on_done: impl FnOnce() + Send + 'static) {
+        if SCAN_STARTED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        thread::Builder::new()
+            .name("font-scan".to_string())
+            .spawn(|| {
+                Self::scan_system_fonts();
+                on_done();
+            })
+            .expect("failed to spawn font scan");
+    }
+
+    pub(crate) fn system_fonts_scanned() -> bool {
+        SYSTEM_SCAN.is_completed()
+    }
+
+    /// Makes sure `family` is registered: from the font file at `path`,
+    /// where it was loaded from, or else from the system fonts, scanning
+    /// them if that hasn't happened yet.
+    pub(crate) fn require_family(family: &str, path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+        if Self::get().has_family(family) {
+            return Ok(());
+        }
+        // A file that moved may still be installed under the same family
+        if let Some(path) = path {
+            let _ = Self::require_file(path);
+        }
+        if !Self::get().has_family(family) {
+            Self::scan_system_fonts();
+        }
+        if Self::get().has_family(family) {
+            Ok(())
+        } else {
+            Err(format!("font family \"{family}\" is not installed").into())
+        }
+    }
+
+    pub(crate) fn has_family(&self, family: &str) -> bool {
+        self.faces
+            .iter()
+            .any(|f| f.family.eq_ignore_ascii_case(family))
+    }
+
+    /// Family names, sorted and without duplicates.
+    pub(crate) fn families(&self) -> Vec<&str> {
+        let mut families: Vec<&str> = self.faces.iter().map(|f| f.family.as_str()).collect();
+        families.sort_unstable_by_key(|family| family.to_lowercase());
+        families.dedup();
+        families
+    }
+
+    pub(crate) fn has_italic(&self, family: &str) -> bool {
+        self.faces
+            .iter()
+            .any(|f| f.italic && f.family.eq_ignore_ascii_case(family))
+    }
+
+    /// The face of `family` closest to `weight` and `italic`, or of the
+    /// default family if `family` isn't known.
+    pub(crate) fn find(&self, family: &str, weight: u16, italic: bool) -> &FontFace {
+        let in_family = |family: &str| {
+            self.faces
+                .iter()
+                .filter(move |f| f.family.eq_ignore_ascii_case(family))
+                .min_by_key(|f| {
+                    let weight_gap = if f.variable_weight() {
+                        0
+                    } else {
+                        f.weight.abs_diff(weight)
+                    };
+                    weight_gap + STYLE_MISMATCH_PENALTY * (f.italic != italic) as u16
+                })
+        };
+        in_family(family)
+            .or_else(|| in_family(DEFAULT_FAMILY))
+            .unwrap_or(&self.faces[0])
+    }
+}
+
+/// Registry entries for each face in the font file `data`.
+fn parse_faces(data: &[u8], source: FontSource) -> Vec<FontFace> {
+    (0..fonts_in_collection(data).unwrap_or(1))
+        .filter_map(|index| {
+            let face = Face::parse(data, index).ok()?;
+            face_entry(&face, source.clone(), index)
+        })
+        .collect()
+}
+
+/// Registry entries for each face in the font file at `path`, reading only
+/// the [`LISTING_TABLES`] of each.
+fn list_faces(path: &Path) -> Result<Vec<FontFace>, Box<dyn Error>> {
+    let mut file = FontFile::open(path)?;
+    let header = file.read_at(0, 12)?;
+    let offsets: Vec<u32> = if header.starts_with(b"ttcf") {
+        let count = u32_at(&header, 8);
+        file.read_at(12, count as u64 * 4)?
+            .chunks_exact(4)
+            .map(|offset| u32_at(offset, 0))
+            .collect()
+    } else {
+        vec![0]
+    };
+
+    let source = FontSource::File(path.to_path_buf());
+    let mut faces = vec![];
+    for (index, offset) in offsets.into_iter().enumerate() {
+        let Ok(tables) = file.read_tables(offset) else {
+            continue;
+        };
+        let table = |tag: &[u8; 4]| {
+            tables
+                .iter()
+                .find(|(t, _)| t == tag)
+                .map(|(_, data)| data.as_slice())
+        };
+        let (Some(head), Some(hhea), Some(maxp)) = (table(b"head"), table(b"hhea"), table(b"maxp"))
+        else {
+            continue;
+        };
+        let raw = RawFaceTables {
+            head,
+            hhea,
+            maxp,
+            name: table(b"name"),
+            os2: table(b"OS/2"),
+            fvar: table(b"fvar"),
+            ..Default::default()
+        };
+        if let Ok(face) = Face::from_raw_tables(raw)
+            && let Some(entry) = face_entry(&face, source.clone(), index as u32)
+        {
+            faces.push(entry);
+        }
+    }
+    Ok(faces)
+}
+
+/// The registry entry for `face`, if it has a family name.
+fn face_entry(face: &Face, source: FontSource, index: u32) -> Option<FontFace> {
+    let name = |id: u16| {
+        face.names()
+            .into_iter()
+            .filter(|name| name.name_id == id)
+            .find_map(|name| name.to_string())
+    };
+    // The typographic family groups weights that older apps list as
+    // separate families, e.g. "Roboto Light"
+    let family = name(name_id::TYPOGRAPHIC_FAMILY).or_else(|| name(name_id::FAMILY))?;
+    let axes = face
+        .variation_axes()
+        .into_iter()
+        .filter(|axis| !axis.hidden)
+        .map(|axis| {
+            let tag: String = axis.tag.to_bytes().map(char::from).iter().collect();
+            FontAxis {
+                name: name(axis.name_id).unwrap_or_else(|| tag.clone()),
+                tag,
+                min: axis.min_value,
+                default: axis.def_value,
+                max: axis.max_value,
+            }
+        })
+        .collect();
+    Some(FontFace {
+        family,
+        weight: face.weight().to_number(),
+        italic: face.is_italic() || face.is_oblique(),
+        axes,
+        source,
+        index,
+    })
+}
+
+/// A font file read piecewise.
+struct FontFile {
+    file: File,
+    len: u64,
+}
+
+impl FontFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    /// `len` bytes from `offset`, failing instead of allocating for ranges
+    /// past the end of a malformed file.
+    fn read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        if offset.saturating_add(len) > self.len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut data = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// The [`LISTING_TABLES`] of the face whose table directory is at
+    /// `offset`, by tag.
+    fn read_tables(&mut self, offset: u32) -> io::Result<Vec<([u8; 4], Vec<u8>)>> {
+        let offset = offset as u64;
+        let header = self.read_at(offset, 12)?;
+        let count = u16::from_be_bytes([header[4], header[5]]) as u64;
+        let records = self.read_at(offset + 12, count * 16)?;
+        records
+            .chunks_exact(16)
+            .filter(|record| LISTING_TABLES.iter().any(|tag| record.starts_with(*tag)))
+            .map(|record| {
+                let tag = [record[0], record[1], record[2], record[3]];
+                let data = self.read_at(u32_at(record, 8) as u64, u32_at(record, 12) as u64)?;
+                Ok((tag, data))
+            })
+            .collect()
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Collects the faces of the font files under `dir` into `faces`. Symlinks
+/// are followed, but every directory is only read once, so links pointing
+/// back up the tree don't recurse forever.
+fn scan_dir(dir: &Path, visited: &mut HashSet<PathBuf>, faces: &mut Vec<FontFace>) {
+    let Ok(canonical) = dir.canonicalize() else {
+        return;
+    };
+    if !visited.insert(canonical) {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            scan_dir(&path, visited, faces);
+        } else if is_font_file(&path)
+            && let Ok(found) = list_faces(&path)
+        {
+            faces.extend(found);
+        }
+    }
+}
+
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| FONT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn system_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if cfg!(target_os = "windows") {
+        if let Some(windir) = std::env::var_os("WINDIR") {
+            dirs.push(PathBuf::from(windir).join("Fonts"));
+        }
+        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join("Microsoft/Windows/Fonts"));
+        }
+    } else if cfg!(target_os = "macos") {
+        dirs.extend(["/System/Library/Fonts", "/Library/Fonts"].map(PathBuf::from));
+        dirs.extend(home.map(|home| home.join("Library/Fonts")));
+    } else {
+        dirs.extend(["/usr/share/fonts", "/usr/local/share/fonts"].map(PathBuf::from));
+        if let Some(home) = home {
+            dirs.push(home.join(".local/share/fonts"));
+            dirs.push(home.join(".fonts"));
+        }
+    }
+    dirs
+}
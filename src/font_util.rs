@@ -1,8 +1,81 @@
 
-use ab_glyph::{FontVec, PxScaleFont, ScaleFont};
+use ab_glyph::{Font, FontVec, GlyphId, PxScale, PxScaleFont, ScaleFont};
+use serde::{Deserialize, Serialize};
 
 
-pub(crate) fn calculate_line_width(scaled_font: PxScaleFont<&FontVec>, text: &str) -> f32 {
+/// Horizontal placement of each line within the text block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TextAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+    /// Stretches the spaces of wrapped lines to the block width. Lines that
+    /// end a paragraph aren't stretched, so without wrapping none are.
+    Justify,
+}
+
+impl TextAlign {
+    pub(crate) const ALL: [TextAlign; 4] = [
+        TextAlign::Left,
+        TextAlign::Center,
+        TextAlign::Right,
+        TextAlign::Justify,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            TextAlign::Left => "Left",
+            TextAlign::Center => "Center",
+            TextAlign::Right => "Right",
+            TextAlign::Justify => "Justify",
+        }
+    }
+}
+
+/// Alignment and spacing of multiline text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TextLayout {
+    pub(crate) align: TextAlign,
+    /// Distance between baselines as a multiple of the font's line height.
+    pub(crate) line_height: f32,
+    /// Extra space between letters as a fraction of the font size.
+    pub(crate) letter_spacing: f32,
+    /// Width in pixels to wrap lines at, which the block is then as wide as.
+    pub(crate) max_width: Option<f32>,
+}
+
+impl Default for TextLayout {
+    fn default() -> Self {
+        Self {
+            align: TextAlign::Center,
+            line_height: 1.0,
+            letter_spacing: 0.0,
+            max_width: None,
+        }
+    }
+}
+
+/// One line of a laid out text block.
+pub(crate) struct LaidOutLine<'a> {
+    pub(crate) text: &'a str,
+    /// Left edge from the left of the block.
+    pub(crate) x: f32,
+    /// Top edge from the top of the block.
+    pub(crate) y: f32,
+    /// Space added after each whitespace character to justify the line.
+    pub(crate) stretch: f32,
+}
+
+/// Each glyph of `text` with its pen position from the start of the line,
+/// plus the width of the line.
+pub(crate) fn glyph_positions(
+    scaled_font: PxScaleFont<&FontVec>,
+    text: &str,
+    letter_spacing: f32,
+) -> (Vec<(GlyphId, f32)>, f32) {
+    let tracking = letter_spacing * scaled_font.scale().x;
+    let mut positions = Vec::with_capacity(text.len());
     let mut width = 0.0;
     let mut last_glyph_id = None;
 
@@ -13,12 +86,218 @@ pub(crate) fn calculate_line_width(scaled_font: PxScaleFont<&FontVec>, text: &st
         if let Some(last_id) = last_glyph_id {
             // Important: Unscaled kern units must be multiplied by the scale factor
             // or use the helper: scaled_font.kern(last_id, glyph_id)
-            width += scaled_font.kern(last_id, glyph_id);
+            width += scaled_font.kern(last_id, glyph_id) + tracking;
         }
+        positions.push((glyph_id, width));
 
         // 3. Add the advance width (already scaled)
         width += scaled_font.h_advance(glyph_id);
         last_glyph_id = Some(glyph_id);
     }
-    width
-}
\ No newline at end of file
+    (positions, width)
+}
+
+pub(crate) fn calculate_line_width(
+    scaled_font: PxScaleFont<&FontVec>,
+    text: &str,
+    letter_spacing: f32,
+) -> f32 {
+    glyph_positions(scaled_font, text, letter_spacing).1
+}
+
+/// Breaks each line of `text` into lines no wider than `max_width`, at
+/// whitespace where possible and between characters for words too long for
+/// a line of their own. Each line comes with whether it ends a paragraph.
+pub(crate) fn wrap_lines<'a>(
+    scaled_font: PxScaleFont<&FontVec>,
+    text: &'a str,
+    max_width: f32,
+    letter_spacing: f32,
+) -> Vec<(&'a str, bool)> {
+    let fits = |line: &str| calculate_line_width(scaled_font, line, letter_spacing) <= max_width;
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut rest = paragraph;
+        loop {
+            if fits(rest) {
+                lines.push((rest, true));
+                break;
+            }
+            // The last whitespace the text before it still fits ahead of
+            let word_break = rest
+                .char_indices()
+                .filter(|(i, c)| c.is_whitespace() && !rest[..*i].trim().is_empty())
+                .map(|(i, _)| i)
+                .take_while(|i| fits(rest[..*i].trim_end()))
+                .last();
+            let end = word_break.unwrap_or_else(|| {
+                // At least one character per line, so wrapping always ends
+                rest.char_indices()
+                    .map(|(i, c)| i + c.len_utf8())
+                    .take_while(|end| fits(&rest[..*end]))
+                    .last()
+                    .unwrap_or_else(|| rest.chars().next().map_or(0, char::len_utf8))
+            });
+            lines.push((rest[..end].trim_end(), false));
+            rest = rest[end..].trim_start();
+            if rest.is_empty() {
+                let last = lines.len() - 1;
+                lines[last].1 = true;
+                break;
+            }
+        }
+    }
+    lines
+}
+
+/// Positions the lines of `text` within their block per `layout`, and
+/// returns them with the block's width and height.
+pub(crate) fn layout_lines<'a>(
+    scaled_font: PxScaleFont<&FontVec>,
+    text: &'a str,
+    layout: &TextLayout,
+) -> (Vec<LaidOutLine<'a>>, (f32, f32)) {
+    let lines = match layout.max_width {
+        Some(max_width) => wrap_lines(scaled_font, text, max_width, layout.letter_spacing),
+        None => text.lines().map(|line| (line, true)).collect(),
+    };
+    let widths: Vec<(&str, bool, f32)> = lines
+        .into_iter()
+        .map(|(line, ends_paragraph)| {
+            let width = calculate_line_width(scaled_font, line, layout.letter_spacing);
+            (line, ends_paragraph, width)
+        })
+        .collect();
+    let widest = widths.iter().map(|(_, _, w)| *w).fold(0.0, f32::max);
+    let block_width = layout.max_width.map_or(widest, |max| max.max(widest));
+    let advance = scaled_font.height() * layout.line_height;
+
+    let lines: Vec<LaidOutLine> = widths
+        .into_iter()
+        .enumerate()
+        .map(|(i, (line, ends_paragraph, width))| {
+            let spaces = line.chars().filter(|c| c.is_whitespace()).count();
+            let justify = spaces > 0 && !ends_paragraph;
+            let (x, stretch) = match layout.align {
+                TextAlign::Left => (0.0, 0.0),
+                TextAlign::Center => ((block_width - width) / 2.0, 0.0),
+                TextAlign::Right => (block_width - width, 0.0),
+                TextAlign::Justify if justify => (0.0, (block_width - width) / spaces as f32),
+                TextAlign::Justify => (0.0, 0.0),
+            };
+            LaidOutLine {
+                text: line,
+                x,
+                y: i as f32 * advance,
+                stretch,
+            }
+        })
+        .collect();
+    // The last line takes the font's full height whatever the spacing
+    let height = match lines.len() {
+        0 => 0.0,
+        n => (n - 1) as f32 * advance + scaled_font.height(),
+    };
+    (lines, (block_width, height))
+}
+
+/// Size of `text` as laid out by `draw_multiline_text_mut`: the widest line
+/// by the height of all lines.
+pub(crate) fn measure_multiline_text(
+    font: &FontVec,
+    scale: PxScale,
+    text: &str,
+    layout: &TextLayout,
+) -> (f32, f32) {
+    layout_lines(font.as_scaled(scale), text, layout).1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTO: &[u8] = include_bytes!("../Roboto-VariableFont_wdth,wght.ttf");
+
+    fn wrap(text: &str, max_width: f32) -> Vec<(&str, bool)> {
+        let font = FontVec::try_from_vec(ROBOTO.to_vec()).unwrap();
+        wrap_lines(font.as_scaled(32.0), text, max_width, 0.0)
+    }
+
+    fn width(text: &str) -> f32 {
+        let font = FontVec::try_from_vec(ROBOTO.to_vec()).unwrap();
+        calculate_line_width(font.as_scaled(32.0), text, 0.0)
+    }
+
+    #[test]
+    fn wraps_at_whitespace() {
+        assert_eq!(
+            wrap("word word word word", width("word word")),
+            [("word word", false), ("word word", true)]
+        );
+    }
+
+    #[test]
+    fn breaks_long_words_between_characters() {
+        let word = "incomprehensibilities";
+        let max_width = width("incompre");
+        let lines = wrap(word, max_width);
+        assert!(lines.len() > 1);
+        assert_eq!(
+            lines.iter().map(|(line, _)| *line).collect::<String>(),
+            word
+        );
+        assert!(lines.iter().all(|(line, _)| width(line) <= max_width));
+        // Each line takes as many characters as fit
+        for pair in lines.windows(2) {
+            let next = pair[1].0.chars().next().unwrap();
+            assert!(width(&format!("{}{next}", pair[0].0)) > max_width);
+        }
+    }
+
+    #[test]
+    fn puts_one_character_per_line_when_nothing_fits() {
+        for max_width in [0.0, -10.0, f32::NAN] {
+            assert_eq!(
+                wrap("ab c", max_width),
+                [("a", false), ("b", false), ("c", true)],
+                "max_width {max_width}"
+            );
+        }
+    }
+
+    #[test]
+    fn drops_runs_of_spaces_at_breaks() {
+        assert_eq!(
+            wrap("word    word   word", width("word")),
+            [("word", false), ("word", false), ("word", true)]
+        );
+        // Spaces within a line are kept
+        assert_eq!(wrap("word   word", 1000.0), [("word   word", true)]);
+    }
+
+    #[test]
+    fn keeps_empty_paragraphs() {
+        assert_eq!(
+            wrap("word\n\nword", 1000.0),
+            [("word", true), ("", true), ("word", true)]
+        );
+    }
+
+    #[test]
+    fn flags_the_last_line_of_each_paragraph() {
+        assert_eq!(
+            wrap("word word word\nword word word word", width("word word")),
+            [
+                ("word word", false),
+                ("word", true),
+                ("word word", false),
+                ("word word", true),
+            ]
+        );
+        // Including a long word's last piece
+        let lines = wrap("incomprehensibilities", width("incompre"));
+        let flags: Vec<bool> = lines.iter().map(|(_, ends)| *ends).collect();
+        assert_eq!(flags.iter().filter(|ends| **ends).count(), 1);
+        assert_eq!(flags.last(), Some(&true));
+    }
+}
@@ -0,0 +1,65 @@
+use image::DynamicImage;
+
+use crate::color_util::luma;
+
+/// Where in the pipeline a histogram is taken, by op id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum HistogramAt {
+    Input(usize),
+    Output(usize),
+    /// The rendered result.
+    Final,
+}
+
+/// Pixel counts per value of the red, green, blue and luma channels.
+/// Fully transparent pixels are left out.
+pub(crate) struct Histogram {
+    pub(crate) channels: [[u32; 256]; 4],
+    pub(crate) total: u32,
+    /// Pixels with at least one channel at 0.
+    pub(crate) crushed: u32,
+    /// Pixels with at least one channel at 255.
+    pub(crate) blown: u32,
+}
+
+pub(crate) const LUMA_CHANNEL: usize = 3;
+
+impl Histogram {
+    pub(crate) fn from_image(img: &DynamicImage) -> Self {
+        let mut histogram = Self {
+            channels: [[0; 256]; 4],
+            total: 0,
+            crushed: 0,
+            blown: 0,
+        };
+        let mut count = |rgba: &[u8]| {
+            if rgba[3] == 0 {
+                return;
+            }
+            let rgb = &rgba[..3];
+            for (c, value) in rgb.iter().enumerate() {
+                histogram.channels[c][*value as usize] += 1;
+            }
+            let luma = luma([rgba[0], rgba[1], rgba[2]].map(|v| v as f32 / 255.0));
+            histogram.channels[LUMA_CHANNEL][(luma * 255.0).round() as usize] += 1;
+            histogram.total += 1;
+            histogram.crushed += rgb.contains(&0) as u32;
+            histogram.blown += rgb.contains(&255) as u32;
+        };
+        match img.as_rgba8() {
+            Some(rgba) => rgba.pixels().for_each(|p| count(&p.0)),
+            None => img.to_rgba8().pixels().for_each(|p| count(&p.0)),
+        }
+        histogram
+    }
+
+    /// Fraction of pixels with a channel clipped to black.
+    pub(crate) fn crushed_fraction(&self) -> f32 {
+        self.crushed as f32 / self.total.max(1) as f32
+    }
+
+    /// Fraction of pixels with a channel clipped to white.
+    pub(crate) fn blown_fraction(&self) -> f32 {
+        self.blown as f32 / self.total.max(1) as f32
+    }
+}
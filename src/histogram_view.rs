@@ -0,0 +1,103 @@
+use eframe::egui::{self, Color32, Pos2, Rect};
+use image::DynamicImage;
+
+use crate::histogram::{Histogram, LUMA_CHANNEL};
+
+const CHANNEL_COLORS: [Color32; 3] = [
+    Color32::from_rgb(230, 70, 70),
+    Color32::from_rgb(70, 200, 70),
+    Color32::from_rgb(80, 120, 240),
+];
+/// Overlay colors for pixels clipped to black and to white.
+const CRUSHED_COLOR: Color32 = Color32::from_rgb(40, 90, 255);
+const BLOWN_COLOR: Color32 = Color32::from_rgb(255, 40, 40);
+/// Clipping below this fraction isn't worth a warning.
+const CLIPPING_WARNING: f32 = 0.001;
+
+/// Fills `rect` with one bar per value of `counts`.
+pub(crate) fn paint_channel(
+    painter: &egui::Painter,
+    rect: Rect,
+    counts: &[u32; 256],
+    fill: Color32,
+) {
+    // Square root keeps small counts visible next to a tall peak
+    let max = counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let bar_width = rect.width() / 256.0;
+    for (value, count) in counts.iter().enumerate() {
+        let height = (*count as f32 / max).sqrt() * rect.height();
+        let left = rect.left() + value as f32 * bar_width;
+        painter.rect_filled(
+            Rect::from_min_max(
+                Pos2::new(left, rect.bottom() - height),
+                Pos2::new(left + bar_width, rect.bottom()),
+            ),
+            0.0,
+            fill,
+        );
+    }
+}
+
+/// RGB and luma plots of `histogram` with its clipping percentages.
+pub(crate) fn histogram_view(ui: &mut egui::Ui, histogram: &Histogram) {
+    let width = ui.available_width();
+    let plot = |ui: &mut egui::Ui, label: &str, channels: &[(usize, Color32)]| {
+        ui.label(label);
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(width, 90.0), egui::Sense::hover());
+        let painter = ui.painter_at(rect);
+        painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+        for (channel, color) in channels {
+            paint_channel(&painter, rect, &histogram.channels[*channel], *color);
+        }
+    };
+    let rgb = CHANNEL_COLORS.map(|color| color.gamma_multiply(0.5));
+    plot(ui, "RGB", &[(0, rgb[0]), (1, rgb[1]), (2, rgb[2])]);
+    let luma = ui.visuals().strong_text_color().gamma_multiply(0.6);
+    plot(ui, "Luminance", &[(LUMA_CHANNEL, luma)]);
+
+    ui.add_space(4.0);
+    for (label, fraction, color) in [
+        (
+            "Shadows clipped",
+            histogram.crushed_fraction(),
+            CRUSHED_COLOR,
+        ),
+        (
+            "Highlights clipped",
+            histogram.blown_fraction(),
+            BLOWN_COLOR,
+        ),
+    ] {
+        ui.horizontal(|ui| {
+            ui.label(label);
+            let text = egui::RichText::new(format!("{:.2}%", fraction * 100.0));
+            if fraction >= CLIPPING_WARNING {
+                ui.label(text.color(color).strong());
+            } else {
+                ui.label(text.weak());
+            }
+        });
+    }
+}
+
+/// Marks pixels of `img` with a channel clipped to black or white; every
+/// other pixel is transparent.
+pub(crate) fn clipping_overlay(img: &DynamicImage) -> egui::ColorImage {
+    let rgba = img.to_rgba8();
+    let pixels = rgba
+        .pixels()
+        .map(|p| {
+            let rgb = &p.0[..3];
+            if p.0[3] == 0 {
+                Color32::TRANSPARENT
+            } else if rgb.contains(&255) {
+                BLOWN_COLOR
+            } else if rgb.contains(&0) {
+                CRUSHED_COLOR
+            } else {
+                Color32::TRANSPARENT
+            }
+        })
+        .collect();
+    egui::ColorImage::new([rgba.width() as usize, rgba.height() as usize], pixels)
+}
@@ -0,0 +1,304 @@
+use crate::image_editor::ImageOp;
+
+/// Oldest entries are dropped once the history grows past this.
+const MAX_ENTRIES: usize = 200;
+
+/// A reversible change to the pipeline.
+#[derive(Clone)]
+pub(crate) enum EditCommand {
+    Insert {
+        index: usize,
+        op: ImageOp,
+    },
+    Remove {
+        index: usize,
+        op: ImageOp,
+    },
+    /// `to` is the index the op ends up at.
+    Move {
+        from: usize,
+        to: usize,
+    },
+    /// The op is looked up by id, so the entry survives later reordering.
+    Modify {
+        before: ImageOp,
+        after: ImageOp,
+    },
+    Replace {
+        before: Vec<ImageOp>,
+        after: Vec<ImageOp>,
+    },
+}
+
+impl EditCommand {
+    fn apply(&self, pipeline: &mut Vec<ImageOp>) {
+        match self {
+            EditCommand::Insert { index, op } => pipeline.insert(*index, op.clone()),
+            EditCommand::Remove { index, .. } => {
+                pipeline.remove(*index);
+            }
+            EditCommand::Move { from, to } => {
+                let op = pipeline.remove(*from);
+                pipeline.insert(*to, op);
+            }
+            EditCommand::Modify { after, .. } => replace_by_id(pipeline, after),
+            EditCommand::Replace { after, .. } => *pipeline = after.clone(),
+        }
+    }
+
+    fn revert(&self, pipeline: &mut Vec<ImageOp>) {
+        match self {
+            EditCommand::Insert { index, .. } => {
+                pipeline.remove(*index);
+            }
+            EditCommand::Remove { index, op } => pipeline.insert(*index, op.clone()),
+            EditCommand::Move { from, to } => {
+                let op = pipeline.remove(*to);
+                pipeline.insert(*from, op);
+            }
+            EditCommand::Modify { before, .. } => replace_by_id(pipeline, before),
+            EditCommand::Replace { before, .. } => *pipeline = before.clone(),
+        }
+    }
+
+    pub(crate) fn label(&self) -> String {
+        match self {
+            EditCommand::Insert { op, .. } => format!("Add {}", op.effect.name()),
+            EditCommand::Remove { op, .. } => format!("Remove {}", op.effect.name()),
+            EditCommand::Move { from, to } => format!("Move #{} to #{}", from + 1, to + 1),
+            EditCommand::Modify { before, after } if before.enabled != after.enabled => {
+                let verb = if after.enabled { "Enable" } else { "Bypass" };
+                format!("{verb} {}", after.effect.name())
+            }
+            EditCommand::Modify { after, .. } => format!("Edit {}", after.effect.name()),
+            EditCommand::Replace { .. } => "Load preset".to_string(),
+        }
+    }
+}
+
+fn replace_by_id(pipeline: &mut [ImageOp], op: &ImageOp) {
+    if let Some(slot) = pipeline.iter_mut().find(|slot| slot.id == op.id) {
+        *slot = op.clone();
+    }
+}
+
+/// Undo/redo stack of [`EditCommand`]s.
+///
+/// Commands are recorded after the UI has already changed the pipeline;
+/// undo and redo replay them against it.
+#[derive(Default)]
+pub(crate) struct History {
+    entries: Vec<EditCommand>,
+    /// Number of applied entries; `entries[cursor..]` can be redone.
+    cursor: usize,
+    /// Op id whose `Modify` entry on top of the stack still absorbs edits,
+    /// so a slider drag or a typing burst becomes a single step.
+    coalescing: Option<usize>,
+}
+
+impl History {
+    pub(crate) fn record(&mut self, command: EditCommand) {
+        self.entries.truncate(self.cursor);
+        self.entries.push(command);
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.cursor = self.entries.len();
+        self.coalescing = None;
+    }
+
+    /// Records an edit of a single op. While `continuing` is set, further
+    /// edits of the same op are merged into this entry.
+    pub(crate) fn record_modify(&mut self, before: ImageOp, after: ImageOp, continuing: bool) {
+        let id = after.id;
+        let merge = self.coalescing == Some(id) && self.cursor == self.entries.len();
+        match self.entries.last_mut() {
+            Some(EditCommand::Modify { after: last, .. }) if merge => *last = after,
+            _ => self.record(EditCommand::Modify { before, after }),
+        }
+        self.coalescing = continuing.then_some(id);
+    }
+
+    /// Closes the entry that is currently absorbing edits.
+    pub(crate) fn end_interaction(&mut self) {
+        self.coalescing = None;
+    }
+
+    pub(crate) fn can_undo(&self) -> bool {
+        self.cursor > 0
+    }
+
+    pub(crate) fn can_redo(&self) -> bool {
+        self.cursor < self.entries.len()
+    }
+
+    pub(crate) fn undo(&mut self, pipeline: &mut Vec<ImageOp>) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
+        self.cursor -= 1;
+        self.entries[self.cursor].revert(pipeline);
+        self.coalescing = None;
+        true
+    }
+
+    pub(crate) fn redo(&mut self, pipeline: &mut Vec<ImageOp>) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+        self.entries[self.cursor].apply(pipeline);
+        self.cursor += 1;
+        self.coalescing = None;
+        true
+    }
+
+    /// Undoes or redoes until `position` entries are applied.
+    pub(crate) fn jump_to(&mut self, position: usize, pipeline: &mut Vec<ImageOp>) -> bool {
+        let position = position.min(self.entries.len());
+        let changed = position != self.cursor;
+        while self.cursor > position {
+            self.undo(pipeline);
+        }
+        while self.cursor < position {
+            self.redo(pipeline);
+        }
+        changed
+    }
+
+    pub(crate) fn entries(&self) -> &[EditCommand] {
+        &self.entries
+    }
+
+    pub(crate) fn cursor(&self) -> usize {
+        self.cursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_editor::EffectType;
+
+    fn brightness(id: usize, value: i32) -> ImageOp {
+        ImageOp {
+            id,
+            effect: EffectType::Brightness { value },
+            enabled: true,
+        }
+    }
+
+    /// Id and brightness of each op, since ops compare by id only.
+    fn values(pipeline: &[ImageOp]) -> Vec<(usize, i32)> {
+        pipeline
+            .iter()
+            .map(|op| match op.effect {
+                EffectType::Brightness { value } => (op.id, value),
+                _ => unreachable!("only brightness ops are used"),
+            })
+            .collect()
+    }
+
+    /// Inserts `op` at the end of `pipeline` the way the UI does.
+    fn insert(history: &mut History, pipeline: &mut Vec<ImageOp>, op: ImageOp) {
+        pipeline.push(op.clone());
+        history.record(EditCommand::Insert {
+            index: pipeline.len() - 1,
+            op,
+        });
+    }
+
+    /// Sets the brightness of the op at `index` the way the UI does.
+    fn modify(
+        history: &mut History,
+        pipeline: &mut [ImageOp],
+        index: usize,
+        value: i32,
+        continuing: bool,
+    ) {
+        let before = pipeline[index].clone();
+        pipeline[index].effect = EffectType::Brightness { value };
+        history.record_modify(before, pipeline[index].clone(), continuing);
+    }
+
+    #[test]
+    fn undoes_and_redoes() {
+        let mut history = History::default();
+        let mut pipeline = vec![];
+        insert(&mut history, &mut pipeline, brightness(0, 10));
+        insert(&mut history, &mut pipeline, brightness(1, 20));
+        history.record(EditCommand::Move { from: 1, to: 0 });
+        pipeline.swap(0, 1);
+        assert_eq!(values(&pipeline), [(1, 20), (0, 10)]);
+
+        assert!(history.undo(&mut pipeline));
+        assert_eq!(values(&pipeline), [(0, 10), (1, 20)]);
+        assert!(history.undo(&mut pipeline));
+        assert!(history.undo(&mut pipeline));
+        assert!(pipeline.is_empty());
+        assert!(!history.can_undo());
+        assert!(!history.undo(&mut pipeline));
+
+        assert!(history.redo(&mut pipeline));
+        assert!(history.redo(&mut pipeline));
+        assert!(history.redo(&mut pipeline));
+        assert_eq!(values(&pipeline), [(1, 20), (0, 10)]);
+        assert!(!history.can_redo());
+        assert!(!history.redo(&mut pipeline));
+    }
+
+    #[test]
+    fn coalesces_continuing_edits_of_one_op() {
+        let mut history = History::default();
+        let mut pipeline = vec![];
+        insert(&mut history, &mut pipeline, brightness(0, 0));
+        // A slider drag: every frame continues the entry
+        for value in 1..=5 {
+            modify(&mut history, &mut pipeline, 0, value, true);
+        }
+        history.end_interaction();
+        modify(&mut history, &mut pipeline, 0, 9, false);
+        assert_eq!(history.entries().len(), 3);
+
+        history.undo(&mut pipeline);
+        assert_eq!(values(&pipeline), [(0, 5)]);
+        history.undo(&mut pipeline);
+        assert_eq!(values(&pipeline), [(0, 0)]);
+    }
+
+    #[test]
+    fn does_not_coalesce_across_ops_or_undo() {
+        let mut history = History::default();
+        let mut pipeline = vec![];
+        insert(&mut history, &mut pipeline, brightness(0, 0));
+        insert(&mut history, &mut pipeline, brightness(1, 0));
+        modify(&mut history, &mut pipeline, 0, 1, true);
+        modify(&mut history, &mut pipeline, 1, 1, true);
+        assert_eq!(history.entries().len(), 4);
+
+        history.undo(&mut pipeline);
+        modify(&mut history, &mut pipeline, 0, 2, true);
+        assert_eq!(history.entries().len(), 4);
+        history.undo(&mut pipeline);
+        assert_eq!(values(&pipeline), [(0, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn new_edit_drops_the_redo_entries() {
+        let mut history = History::default();
+        let mut pipeline = vec![];
+        insert(&mut history, &mut pipeline, brightness(0, 10));
+        insert(&mut history, &mut pipeline, brightness(1, 20));
+        history.undo(&mut pipeline);
+        assert!(history.can_redo());
+
+        insert(&mut history, &mut pipeline, brightness(2, 30));
+        assert!(!history.can_redo());
+        assert_eq!(history.entries().len(), 2);
+        assert_eq!(history.cursor(), 2);
+
+        history.undo(&mut pipeline);
+        history.undo(&mut pipeline);
+        history.jump_to(2, &mut pipeline);
+        assert_eq!(values(&pipeline), [(0, 10), (2, 30)]);
+    }
+}
@@ -1,43 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use eframe::egui::Color32;
-use image::DynamicImage;
+use image::{DynamicImage, ImageReader, Rgba, imageops::FilterType};
+use imageproc::geometric_transformations::{
+    Interpolation, rotate_about_center, rotate_about_center_no_crop,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blend_util::BlendMode,
+    color_util,
+    curve_util::CurvesParams,
+    export_util::{ExportOptions, export_image},
+    filter_util::{self, EdgeMethod},
+    font_registry::{DEFAULT_FAMILY, FontRegistry},
+    font_util::{TextAlign, TextLayout},
+    imageproc_util::draw_watermark,
+    pipeline_cache::pipeline_key,
+};
 
-use crate::imageproc_util::draw_watermark;
+/// File extensions offered by the open dialog. Decoding itself sniffs the
+/// content, so a misnamed file still opens as long as `image` supports it.
+pub(crate) const SUPPORTED_EXTENSIONS: &[&str] =
+    &["png", "jpg", "jpeg", "webp", "tif", "tiff", "bmp", "gif"];
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct WatermarkParams {
     pub text: String,
+    pub font_family: String,
+    /// Font file `font_family` was loaded from, so presets can load it again.
+    /// `None` for the bundled fonts.
+    pub font_path: Option<PathBuf>,
+    /// CSS-style weight, 400 being regular and 700 bold.
+    pub weight: u16,
+    pub italic: bool,
+    /// Variable font axis coordinates by tag, e.g. `wdth`. A `wght` entry
+    /// overrides `weight`.
+    pub variations: BTreeMap<String, f32>,
     pub color: Color32,
     pub x: i32,
     pub y: i32,
     pub scale: f32,
     pub degree: f32,
+    pub align: TextAlign,
+    /// Multiple of the font's line height between lines.
+    pub line_height: f32,
+    /// Extra space between letters as a fraction of the font size.
+    pub letter_spacing: f32,
+    pub wrap_width: WrapWidth,
+    pub blend_mode: BlendMode,
+    pub opacity: f32,
+    /// Outline thickness in pixels; 0 draws none.
+    pub outline_width: f32,
+    pub outline_color: Color32,
+    pub shadow: bool,
+    /// Shadow displacement in pixels, before rotation.
+    pub shadow_offset: [f32; 2],
+    pub shadow_blur: f32,
+    pub shadow_color: Color32,
+    /// Repeats the text over the whole image on a grid turned by `degree`.
+    pub tiled: bool,
+    /// Horizontal and vertical gap between tiles in pixels.
+    pub tile_spacing: [f32; 2],
+    /// Shift of every other row as a fraction of the column pitch.
+    pub tile_stagger: f32,
 }
 
 impl Default for WatermarkParams {
     fn default() -> Self {
         Self {
             text: "My Watermark\nMultiline".to_string(),
+            font_family: DEFAULT_FAMILY.to_string(),
+            font_path: None,
+            weight: 400,
+            italic: false,
+            variations: BTreeMap::new(),
             color: Color32::from_rgb(0, 0, 0),
             x: -190,
             y: -190,
             scale: 24.0,
             degree: -45.0,
+            align: TextAlign::Center,
+            line_height: 1.0,
+            letter_spacing: 0.0,
+            wrap_width: WrapWidth::Off,
+            blend_mode: BlendMode::Normal,
+            opacity: 1.0,
+            outline_width: 0.0,
+            outline_color: Color32::WHITE,
+            shadow: false,
+            shadow_offset: [4.0, 4.0],
+            shadow_blur: 3.0,
+            shadow_color: Color32::from_black_alpha(160),
+            tiled: false,
+            tile_spacing: [80.0, 80.0],
+            tile_stagger: 0.5,
         }
     }
 }
 
-#[derive(Clone, Debug)]
+impl WatermarkParams {
+    /// Layout of the text on an image `image_width` pixels wide.
+    pub(crate) fn text_layout(&self, image_width: u32) -> TextLayout {
+        TextLayout {
+            align: self.align,
+            line_height: self.line_height,
+            letter_spacing: self.letter_spacing,
+            max_width: self.wrap_width.pixels(image_width),
+        }
+    }
+}
+
+/// Width watermark text wraps at.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub enum WrapWidth {
+    #[default]
+    Off,
+    Pixels(f32),
+    /// Percentage of the image width.
+    Percent(f32),
+}
+
+impl WrapWidth {
+    pub(crate) fn pixels(self, image_width: u32) -> Option<f32> {
+        match self {
+            WrapWidth::Off => None,
+            WrapWidth::Pixels(width) => Some(width),
+            WrapWidth::Percent(percent) => Some(image_width as f32 * percent / 100.0),
+        }
+    }
+}
+
+/// Filters offered by the resize effect; mirrors [`FilterType`], which
+/// doesn't implement serde.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub(crate) const ALL: [ResizeFilter; 5] = [
+        ResizeFilter::Nearest,
+        ResizeFilter::Triangle,
+        ResizeFilter::CatmullRom,
+        ResizeFilter::Gaussian,
+        ResizeFilter::Lanczos3,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "Nearest",
+            ResizeFilter::Triangle => "Bilinear",
+            ResizeFilter::CatmullRom => "Catmull-Rom",
+            ResizeFilter::Gaussian => "Gaussian",
+            ResizeFilter::Lanczos3 => "Lanczos",
+        }
+    }
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) enum EffectType {
-    Blur { sigma: f32 },
-    Brightness { value: i32 },
-    Contrast { value: f32 },
-    Watermark { params: WatermarkParams },
+    Blur {
+        sigma: f32,
+    },
+    Brightness {
+        value: i32,
+    },
+    Contrast {
+        value: f32,
+    },
+    Watermark {
+        params: WatermarkParams,
+    },
+    /// Rectangle in pixels of the op's input; clamped to it when applied.
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        /// Width:height ratio the editor keeps the rectangle at.
+        #[serde(default)]
+        aspect: Option<[u32; 2]>,
+    },
+    Resize {
+        width: u32,
+        height: u32,
+        #[serde(default)]
+        filter: ResizeFilter,
+        /// Makes the editor keep the input's aspect ratio.
+        #[serde(default)]
+        keep_aspect: bool,
+    },
+    /// Clockwise rotation about the center. `expand` grows the canvas to fit
+    /// the rotated image instead of cutting off its corners.
+    Rotate {
+        degrees: f32,
+        #[serde(default)]
+        expand: bool,
+    },
+    Flip {
+        horizontal: bool,
+        vertical: bool,
+    },
+    HueShift {
+        degrees: f32,
+    },
+    /// Both as fractions added to 1.0: -1.0 removes all color.
+    Saturation {
+        saturation: f32,
+        vibrance: f32,
+    },
+    /// Both in -1.0..=1.0.
+    WhiteBalance {
+        temperature: f32,
+        tint: f32,
+    },
+    Gamma {
+        gamma: f32,
+    },
+    Exposure {
+        stops: f32,
+    },
+    Levels {
+        black: u8,
+        white: u8,
+        /// Midtone gamma; above 1.0 brightens.
+        mid: f32,
+    },
+    Invert,
+    Grayscale,
+    Sepia {
+        amount: f32,
+    },
+    Curves {
+        curves: CurvesParams,
+    },
+    /// Differences to a blur of `radius` below `threshold` are left alone.
+    UnsharpMask {
+        amount: f32,
+        radius: f32,
+        threshold: u8,
+    },
+    Sharpen,
+    Median {
+        radius: u32,
+    },
+    Bilateral {
+        radius: u8,
+        spatial_sigma: f32,
+        color_sigma: f32,
+    },
+    /// `low` and `high` are the Canny thresholds.
+    EdgeDetect {
+        method: EdgeMethod,
+        low: f32,
+        high: f32,
+    },
+    /// Row-major `size`x`size` weights.
+    Convolve {
+        kernel: Vec<f32>,
+        size: u32,
+        normalize: bool,
+    },
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct ImageOp {
     pub(crate) id: usize,
     pub(crate) effect: EffectType,
+    /// Bypassed ops keep their settings but are skipped when processing.
+    #[serde(default = "enabled_by_default")]
+    pub(crate) enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 impl PartialEq for ImageOp {
@@ -52,21 +309,26 @@ impl std::hash::Hash for ImageOp {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct ImageEditor {
     pub(crate) pipeline: Vec<ImageOp>,
     pub(crate) next_id: usize,
-    pub(crate) original_image: DynamicImage,
-    pub(crate) final_image: Option<DynamicImage>,
+    pub(crate) original_image: Arc<DynamicImage>,
+    pub(crate) final_image: Option<Arc<DynamicImage>>,
+    pub(crate) source_path: Option<PathBuf>,
+    /// [`pipeline_key`] of the pipeline `final_image` was rendered with.
+    final_key: Option<u64>,
 }
 
 impl ImageEditor {
     pub(crate) fn new_image_op(&mut self, effect: EffectType) -> ImageOp {
         let img_op = ImageOp {
             id: self.next_id,
-            effect: effect,
+            effect,
+            enabled: true,
         };
         self.next_id += 1;
-        return img_op;
+        img_op
     }
 
     pub(crate) fn push_new_img_op(&mut self, effect: EffectType) {
@@ -74,6 +336,29 @@ impl ImageEditor {
         self.pipeline.push(img_op);
     }
 
+    /// Inserts a copy of the op at `index` right after it and returns the
+    /// copy's position.
+    pub(crate) fn duplicate_img_op(&mut self, index: usize) -> usize {
+        let source = &self.pipeline[index];
+        let enabled = source.enabled;
+        let mut img_op = self.new_image_op(source.effect.clone());
+        img_op.enabled = enabled;
+        self.pipeline.insert(index + 1, img_op);
+        index + 1
+    }
+
+    /// Replaces the pipeline with `ops`, giving each op a fresh id so ids
+    /// coming from a preset can't collide with ones handed out before.
+    pub(crate) fn set_pipeline(&mut self, ops: Vec<ImageOp>) {
+        self.pipeline = ops
+            .into_iter()
+            .map(|op| ImageOp {
+                id: self.new_image_op(op.effect.clone()).id,
+                ..op
+            })
+            .collect();
+    }
+
     pub(crate) fn new() -> Self {
         // Create a dummy gradient image
         let img = image::ImageBuffer::from_fn(512, 512, |x, y| {
@@ -84,34 +369,420 @@ impl ImageEditor {
         Self {
             pipeline: vec![],
             next_id: 0,
-            original_image: dynamic_img,
+            original_image: Arc::new(dynamic_img),
             final_image: None,
+            source_path: None,
+            final_key: None,
         }
     }
 
+    /// Replaces the original image with the file at `path`.
+    pub(crate) fn open(&mut self, path: impl AsRef<Path>) -> image::ImageResult<()> {
+        let path = path.as_ref();
+        self.original_image = Arc::new(load_image(path)?);
+        self.final_image = None;
+        self.final_key = None;
+        self.source_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Writes the processed image to `path`, running the pipeline first if
+    /// `final_image` doesn't reflect the current pipeline.
+    pub(crate) fn export(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: ExportOptions,
+    ) -> image::ImageResult<()> {
+        let img = match self.current_final_image() {
+            Some(img) => img,
+            None => {
+                self.process_image();
+                self.final_image
+                    .clone()
+                    .expect("process_image always sets final_image")
+            }
+        };
+        export_image(&img, path.as_ref(), &options)
+    }
+
+    /// `final_image` if it was rendered from the current pipeline.
+    pub(crate) fn current_final_image(&self) -> Option<Arc<DynamicImage>> {
+        self.final_image
+            .clone()
+            .filter(|_| self.final_key == Some(pipeline_key(&self.pipeline)))
+    }
+
+    /// Stores a result rendered elsewhere (see `RenderWorker`) for
+    /// `pipeline_key`.
+    pub(crate) fn set_final_image(&mut self, image: Arc<DynamicImage>, pipeline_key: u64) {
+        self.final_image = Some(image);
+        self.final_key = Some(pipeline_key);
+    }
+
+    /// Size of the image entering each op, plus the output size last.
+    pub(crate) fn pipeline_sizes(&self) -> Vec<[u32; 2]> {
+        let source = &self.original_image;
+        pipeline_sizes([source.width(), source.height()], &self.pipeline)
+    }
+
+    /// Renders the pipeline synchronously.
     pub(crate) fn process_image(&mut self) {
-        let mut img = self.original_image.clone();
-
-        for op in &self.pipeline {
-            match &op.effect {
-                EffectType::Blur { sigma } => {
-                    // Check for 0.0 to prevent crash on some blur implementations
-                    if *sigma > 0.0 {
-                        img = img.blur(*sigma);
-                    }
+        let img = apply_pipeline((*self.original_image).clone(), &self.pipeline);
+        self.set_final_image(Arc::new(img), pipeline_key(&self.pipeline));
+    }
+}
+
+impl EffectType {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EffectType::Blur { .. } => "Blur",
+            EffectType::Brightness { .. } => "Brightness",
+            EffectType::Contrast { .. } => "Contrast",
+            EffectType::Watermark { .. } => "Watermark",
+            EffectType::Crop { .. } => "Crop",
+            EffectType::Resize { .. } => "Resize",
+            EffectType::Rotate { .. } => "Rotate",
+            EffectType::Flip { .. } => "Flip",
+            EffectType::HueShift { .. } => "Hue Shift",
+            EffectType::Saturation { .. } => "Saturation",
+            EffectType::WhiteBalance { .. } => "White Balance",
+            EffectType::Gamma { .. } => "Gamma",
+            EffectType::Exposure { .. } => "Exposure",
+            EffectType::Levels { .. } => "Levels",
+            EffectType::Invert => "Invert",
+            EffectType::Grayscale => "Grayscale",
+            EffectType::Sepia { .. } => "Sepia",
+            EffectType::Curves { .. } => "Curves",
+            EffectType::UnsharpMask { .. } => "Unsharp Mask",
+            EffectType::Sharpen => "Sharpen",
+            EffectType::Median { .. } => "Median",
+            EffectType::Bilateral { .. } => "Bilateral",
+            EffectType::EdgeDetect { .. } => "Edge Detect",
+            EffectType::Convolve { .. } => "Convolve",
+        }
+    }
+
+    /// Size of the image this effect produces from an input of `size`.
+    pub(crate) fn output_size(&self, size: [u32; 2]) -> [u32; 2] {
+        match self {
+            EffectType::Crop { .. } => {
+                let [_, _, width, height] = self.crop_rect(size);
+                [width, height]
+            }
+            EffectType::Resize { width, height, .. } => [(*width).max(1), (*height).max(1)],
+            EffectType::Rotate { degrees, expand } if *expand => match quarter_turns(*degrees) {
+                Some(1 | 3) => [size[1], size[0]],
+                Some(_) => size,
+                None => {
+                    // Same computation as rotate_about_center_no_crop
+                    let (sin, cos) = degrees.to_radians().sin_cos();
+                    let [width, height] = size.map(|v| v as f32);
+                    [
+                        (height * sin.abs() + width * cos.abs()).ceil() as u32,
+                        (height * cos.abs() + width * sin.abs()).ceil() as u32,
+                    ]
                 }
-                EffectType::Brightness { value } => {
-                    img = img.brighten(*value);
+            },
+            _ => size,
+        }
+    }
+
+    /// The crop rectangle as `[x, y, width, height]`, clamped to an input of
+    /// `size` and at least one pixel in each direction.
+    pub(crate) fn crop_rect(&self, size: [u32; 2]) -> [u32; 4] {
+        let EffectType::Crop {
+            x,
+            y,
+            width,
+            height,
+            ..
+        } = self
+        else {
+            return [0, 0, size[0], size[1]];
+        };
+        let x = (*x).min(size[0].saturating_sub(1));
+        let y = (*y).min(size[1].saturating_sub(1));
+        [
+            x,
+            y,
+            (*width).clamp(1, size[0] - x),
+            (*height).clamp(1, size[1] - y),
+        ]
+    }
+
+    /// The same effect for an image scaled by `factor`, so that it looks the
+    /// same on a downscaled proxy as on the full image.
+    pub(crate) fn scaled(&self, factor: f32) -> EffectType {
+        match self {
+            EffectType::Blur { sigma } => EffectType::Blur {
+                sigma: sigma * factor,
+            },
+            EffectType::Brightness { .. }
+            | EffectType::Contrast { .. }
+            | EffectType::Rotate { .. }
+            | EffectType::Flip { .. }
+            | EffectType::HueShift { .. }
+            | EffectType::Saturation { .. }
+            | EffectType::WhiteBalance { .. }
+            | EffectType::Gamma { .. }
+            | EffectType::Exposure { .. }
+            | EffectType::Levels { .. }
+            | EffectType::Invert
+            | EffectType::Grayscale
+            | EffectType::Sepia { .. }
+            | EffectType::Curves { .. }
+            | EffectType::Sharpen
+            | EffectType::EdgeDetect { .. }
+            | EffectType::Convolve { .. } => self.clone(),
+            EffectType::UnsharpMask {
+                amount,
+                radius,
+                threshold,
+            } => EffectType::UnsharpMask {
+                amount: *amount,
+                radius: radius * factor,
+                threshold: *threshold,
+            },
+            EffectType::Median { radius } => EffectType::Median {
+                radius: (*radius as f32 * factor).round() as u32,
+            },
+            EffectType::Bilateral {
+                radius,
+                spatial_sigma,
+                color_sigma,
+            } => EffectType::Bilateral {
+                radius: (*radius as f32 * factor).round().max(1.0) as u8,
+                spatial_sigma: spatial_sigma * factor,
+                color_sigma: *color_sigma,
+            },
+            EffectType::Watermark { params } => EffectType::Watermark {
+                params: WatermarkParams {
+                    x: (params.x as f32 * factor).round() as i32,
+                    y: (params.y as f32 * factor).round() as i32,
+                    scale: params.scale * factor,
+                    outline_width: params.outline_width * factor,
+                    shadow_offset: params.shadow_offset.map(|v| v * factor),
+                    shadow_blur: params.shadow_blur * factor,
+                    tile_spacing: params.tile_spacing.map(|v| v * factor),
+                    wrap_width: match params.wrap_width {
+                        WrapWidth::Pixels(width) => WrapWidth::Pixels(width * factor),
+                        wrap_width => wrap_width,
+                    },
+                    ..params.clone()
+                },
+            },
+            EffectType::Crop {
+                x,
+                y,
+                width,
+                height,
+                aspect,
+            } => EffectType::Crop {
+                x: (*x as f32 * factor).round() as u32,
+                y: (*y as f32 * factor).round() as u32,
+                width: scale_px(*width, factor),
+                height: scale_px(*height, factor),
+                aspect: *aspect,
+            },
+            EffectType::Resize {
+                width,
+                height,
+                filter,
+                keep_aspect,
+            } => EffectType::Resize {
+                width: scale_px(*width, factor),
+                height: scale_px(*height, factor),
+                filter: *filter,
+                keep_aspect: *keep_aspect,
+            },
+        }
+    }
+
+    pub(crate) fn apply(&self, mut img: DynamicImage) -> DynamicImage {
+        match self {
+            EffectType::Blur { sigma } => {
+                // Check for 0.0 to prevent crash on some blur implementations
+                if *sigma > 0.0 {
+                    img = img.blur(*sigma);
                 }
-                EffectType::Contrast { value } => {
-                    img = img.adjust_contrast(*value);
+            }
+            EffectType::Brightness { value } => {
+                img = img.brighten(*value);
+            }
+            EffectType::Contrast { value } => {
+                img = img.adjust_contrast(*value);
+            }
+            EffectType::Watermark { params } => {
+                let _ = draw_watermark(&mut img, params);
+            }
+            EffectType::Crop { .. } => {
+                let [x, y, width, height] = self.crop_rect([img.width(), img.height()]);
+                img = img.crop_imm(x, y, width, height);
+            }
+            EffectType::Resize { filter, .. } => {
+                let [width, height] = self.output_size([img.width(), img.height()]);
+                img = img.resize_exact(width, height, filter.filter_type());
+            }
+            EffectType::Rotate { degrees, expand } => {
+                img = match quarter_turns(*degrees) {
+                    Some(0) => img,
+                    Some(2) => img.rotate180(),
+                    Some(1) if *expand => img.rotate90(),
+                    Some(3) if *expand => img.rotate270(),
+                    _ => {
+                        let rgba = img.into_rgba8();
+                        let theta = degrees.to_radians();
+                        let transparent = Rgba([0, 0, 0, 0]);
+                        DynamicImage::ImageRgba8(if *expand {
+                            rotate_about_center_no_crop(
+                                &rgba,
+                                theta,
+                                Interpolation::Bilinear,
+                                transparent,
+                            )
+                        } else {
+                            rotate_about_center(&rgba, theta, Interpolation::Bilinear, transparent)
+                        })
+                    }
+                };
+            }
+            EffectType::Flip {
+                horizontal,
+                vertical,
+            } => {
+                if *horizontal {
+                    img = img.fliph();
                 }
-                EffectType::Watermark { params } => {
-                    let _ = draw_watermark(&mut img, params);
+                if *vertical {
+                    img = img.flipv();
                 }
             }
+            EffectType::HueShift { degrees } => {
+                img = color_util::hue_shift(img, *degrees);
+            }
+            EffectType::Saturation {
+                saturation,
+                vibrance,
+            } => {
+                img = color_util::saturate(img, *saturation, *vibrance);
+            }
+            EffectType::WhiteBalance { temperature, tint } => {
+                img = color_util::white_balance(img, *temperature, *tint);
+            }
+            EffectType::Gamma { gamma } => {
+                img = color_util::apply_lut(img, &color_util::gamma_lut(*gamma));
+            }
+            EffectType::Exposure { stops } => {
+                img = color_util::apply_lut(img, &color_util::exposure_lut(*stops));
+            }
+            EffectType::Levels { black, white, mid } => {
+                img = color_util::apply_lut(img, &color_util::levels_lut(*black, *white, *mid));
+            }
+            EffectType::Invert => {
+                img = color_util::apply_lut(img, &color_util::invert_lut());
+            }
+            EffectType::Grayscale => {
+                img = color_util::grayscale(img);
+            }
+            EffectType::Sepia { amount } => {
+                img = color_util::sepia(img, *amount);
+            }
+            EffectType::Curves { curves } => {
+                img = curves.apply(img);
+            }
+            EffectType::UnsharpMask {
+                amount,
+                radius,
+                threshold,
+            } => {
+                img = filter_util::unsharp_mask(img, *amount, *radius, *threshold);
+            }
+            EffectType::Sharpen => {
+                img = filter_util::sharpen3x3(img);
+            }
+            EffectType::Median { radius } => {
+                img = filter_util::median(img, *radius);
+            }
+            EffectType::Bilateral {
+                radius,
+                spatial_sigma,
+                color_sigma,
+            } => {
+                img = filter_util::bilateral(img, *radius, *spatial_sigma, *color_sigma);
+            }
+            EffectType::EdgeDetect { method, low, high } => {
+                img = filter_util::edges(img, *method, *low, *high);
+            }
+            EffectType::Convolve {
+                kernel,
+                size,
+                normalize,
+            } => {
+                img = filter_util::convolve(img, kernel, *size, *normalize);
+            }
         }
+        img
+    }
+}
 
-        self.final_image = Some(img);
+/// Scales a width or height, keeping it at least one pixel.
+fn scale_px(value: u32, factor: f32) -> u32 {
+    ((value as f32 * factor).round() as u32).max(1)
+}
+
+/// Number of clockwise quarter turns (0..4) if `degrees` is a multiple of
+/// 90, which can be rotated exactly.
+fn quarter_turns(degrees: f32) -> Option<u32> {
+    let turns = degrees / 90.0;
+    (turns.fract() == 0.0).then(|| turns.rem_euclid(4.0) as u32)
+}
+
+/// Size of the image entering each op of `pipeline` when run over an image
+/// of `size`, plus the final output size as the last element.
+pub(crate) fn pipeline_sizes(size: [u32; 2], pipeline: &[ImageOp]) -> Vec<[u32; 2]> {
+    let mut sizes = vec![size];
+    for op in pipeline {
+        let size = *sizes.last().expect("starts non-empty");
+        sizes.push(if op.enabled {
+            op.effect.output_size(size)
+        } else {
+            size
+        });
     }
+    sizes
+}
+
+/// Makes sure the fonts of the pipeline's watermarks are registered, see
+/// [`FontRegistry::require_family`]. Returns an error per missing family.
+pub(crate) fn require_fonts(pipeline: &[ImageOp]) -> Vec<String> {
+    let mut missing: Vec<String> = pipeline
+        .iter()
+        .filter_map(|op| match &op.effect {
+            EffectType::Watermark { params } => {
+                FontRegistry::require_family(&params.font_family, params.font_path.as_deref())
+                    .err()
+                    .map(|err| err.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    missing.dedup();
+    missing
+}
+
+/// Runs every enabled op of `pipeline` over `img`, in order.
+pub(crate) fn apply_pipeline(img: DynamicImage, pipeline: &[ImageOp]) -> DynamicImage {
+    pipeline
+        .iter()
+        .filter(|op| op.enabled)
+        .fold(img, |img, op| op.effect.apply(img))
+}
+
+/// Decodes the image at `path`.
+///
+/// Animated formats (GIF) only contribute their first frame. The result is
+/// normalized to 8-bit RGBA, which the effects and the texture upload rely on.
+pub(crate) fn load_image(path: &Path) -> image::ImageResult<DynamicImage> {
+    let img = ImageReader::open(path)?.with_guessed_format()?.decode()?;
+    Ok(DynamicImage::ImageRgba8(img.into_rgba8()))
 }
@@ -1,38 +1,847 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, TryRecvError},
+    },
+    thread,
+};
+
 use eframe::egui;
 use egui_dnd::dnd;
-use image::DynamicImage;
+use image::{DynamicImage, GenericImageView};
+
+use crate::{
+    blend_util::BlendMode,
+    canvas_tools::CanvasTools,
+    curve_editor::curves_editor,
+    curve_util::CurvesParams,
+    export_util::{ExportFormat, ExportOptions},
+    filter_util::{EdgeMethod, identity_kernel},
+    font_picker::font_picker,
+    font_registry::FontRegistry,
+    font_util::TextAlign,
+    histogram::{Histogram, HistogramAt},
+    histogram_view::{clipping_overlay, histogram_view},
+    history::{EditCommand, History},
+    image_editor::{
+        EffectType, ImageEditor, ImageOp, ResizeFilter, SUPPORTED_EXTENSIONS, WatermarkParams,
+        WrapWidth, require_fonts,
+    },
+    pipeline_cache::pipeline_key,
+    preset::{PRESET_EXTENSION, PresetFormat, load_preset, save_preset},
+    render_worker::{RenderResult, RenderWorker, WorkerEvent},
+    viewer::{CompareMode, Viewer},
+};
+
+/// Aspect ratios offered by the crop editor; `None` is a free rectangle.
+const CROP_ASPECTS: [(&str, Option<[u32; 2]>); 8] = [
+    ("Free", None),
+    ("1:1", Some([1, 1])),
+    ("4:3", Some([4, 3])),
+    ("3:2", Some([3, 2])),
+    ("16:9", Some([16, 9])),
+    ("3:4", Some([3, 4])),
+    ("2:3", Some([2, 3])),
+    ("9:16", Some([9, 16])),
+];
 
-use crate::image_editor::{EffectType, ImageEditor, WatermarkParams};
+const UNDO: egui::KeyboardShortcut =
+    egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::Z);
+const REDO: egui::KeyboardShortcut = egui::KeyboardShortcut::new(
+    egui::Modifiers::COMMAND.plus(egui::Modifiers::SHIFT),
+    egui::Key::Z,
+);
 
 pub(crate) struct ImageEditorUi {
     img_editor: ImageEditor,
     display_texture: Option<egui::TextureHandle>,
+    /// Full-resolution size of the displayed image; proxy textures are
+    /// smaller and get stretched to it.
+    display_size: egui::Vec2,
+    /// Texture of `original_image` for comparisons, with the image it was
+    /// made from.
+    original_texture: Option<(Arc<DynamicImage>, egui::TextureHandle)>,
+    viewer: Viewer,
+    compare: CompareMode,
+    /// Divider position in split mode, as a fraction of the view width.
+    split: f32,
+    /// Set while the "hold for original" button is pressed.
+    hold_original: bool,
+    canvas_tools: CanvasTools,
+    /// Id of the op whose handles are shown on the canvas.
+    canvas_op: Option<usize>,
+    /// Fit the next render into the view (set when a new image is opened).
+    fit_next_render: bool,
+    render_worker: RenderWorker,
+    /// [`FontRegistry::generation`] the preview was last rendered with.
+    font_generation: u64,
+    /// Fonts the last loaded preset is missing, checked on a background
+    /// thread since that may wait for the system font scan.
+    font_check: Option<Receiver<Vec<String>>>,
+    /// Latest full-resolution render shown, which is where the status bar
+    /// reads pixel values.
+    preview_image: Option<Arc<DynamicImage>>,
+    /// Set while the preview shows a proxy render that still needs its
+    /// full-resolution pass.
+    needs_full_render: bool,
+    /// Histograms from the latest render, see [`Self::histogram_requests`].
+    histograms: HashMap<HistogramAt, Arc<Histogram>>,
+    show_histogram: bool,
+    /// Op whose output the histogram panel shows; the final image if unset.
+    histogram_source: Option<usize>,
+    show_clipping: bool,
+    /// Clipped pixels of the displayed image, drawn over it.
+    clipping_texture: Option<egui::TextureHandle>,
     dirty: bool,
+    error: Option<String>,
+    show_export: bool,
+    export_options: ExportOptions,
+    /// Path of the export the render worker is writing.
+    exporting: Option<PathBuf>,
+    history: History,
+    show_history: bool,
+    /// Op ids in the order they had when the current drag & drop started.
+    drag_start_order: Option<Vec<usize>>,
 }
 
 impl ImageEditorUi {
-    pub(crate) fn new(_cc: &eframe::CreationContext<'_>) -> Self {
+    pub(crate) fn new(cc: &eframe::CreationContext<'_>) -> Self {
         Self {
             img_editor: ImageEditor::new(),
             display_texture: None,
+            display_size: egui::Vec2::ZERO,
+            original_texture: None,
+            viewer: Viewer::default(),
+            compare: CompareMode::Off,
+            split: 0.5,
+            hold_original: false,
+            canvas_tools: CanvasTools::default(),
+            canvas_op: None,
+            fit_next_render: true,
+            render_worker: RenderWorker::spawn(cc.egui_ctx.clone()),
+            font_generation: FontRegistry::generation(),
+            font_check: None,
+            preview_image: None,
+            needs_full_render: false,
+            histograms: HashMap::new(),
+            show_histogram: false,
+            histogram_source: None,
+            show_clipping: false,
+            clipping_texture: None,
             dirty: true,
+            error: None,
+            show_export: false,
+            export_options: ExportOptions::default(),
+            exporting: None,
+            history: History::default(),
+            show_history: false,
+            drag_start_order: None,
+        }
+    }
+
+    fn add_effect(&mut self, effect: EffectType) {
+        self.img_editor.push_new_img_op(effect);
+        let index = self.img_editor.pipeline.len() - 1;
+        self.history.record(EditCommand::Insert {
+            index,
+            op: self.img_editor.pipeline[index].clone(),
+        });
+        self.dirty = true;
+    }
+
+    fn undo(&mut self) {
+        if self.history.undo(&mut self.img_editor.pipeline) {
+            self.dirty = true;
         }
     }
 
-    fn update_texture(&mut self, ctx: &egui::Context) {
-        self.img_editor.process_image();
-        if let Some(img) = &self.img_editor.final_image {
-            self.display_texture = Some(ctx.load_texture(
-                "display",
-                egui::ColorImage::from_rgba_unmultiplied(
-                    [img.width() as usize, img.height() as usize],
-                    img.as_bytes(),
-                ),
-                Default::default(),
+    fn redo(&mut self) {
+        if self.history.redo(&mut self.img_editor.pipeline) {
+            self.dirty = true;
+        }
+    }
+
+    fn handle_shortcuts(&mut self, ctx: &egui::Context) {
+        // A focused text field handles Ctrl+Z itself
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        // Check redo first: the undo shortcut also matches with Shift held
+        if ctx.input_mut(|i| i.consume_shortcut(&REDO)) {
+            self.redo();
+        } else if ctx.input_mut(|i| i.consume_shortcut(&UNDO)) {
+            self.undo();
+        }
+        if self.canvas_op.is_some()
+            && ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Escape))
+        {
+            self.canvas_op = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Position of the op being edited on the canvas.
+    fn canvas_op_index(&self) -> Option<usize> {
+        let id = self.canvas_op?;
+        self.img_editor.pipeline.iter().position(|op| op.id == id)
+    }
+
+    /// Where the preview stops short of the final image, while an op is
+    /// edited on the canvas.
+    fn preview_note(&self) -> Option<String> {
+        let index = self.canvas_op_index()?;
+        let op = &self.img_editor.pipeline[index];
+        if CanvasTools::shows_input(&op.effect) {
+            Some(format!(
+                "Showing the input of #{} {}",
+                index + 1,
+                op.effect.name()
+            ))
+        } else if index + 1 < self.img_editor.pipeline.len() {
+            Some(format!(
+                "Showing the output of #{} {}",
+                index + 1,
+                op.effect.name()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The pipeline to preview. While an op is edited on the canvas the
+    /// preview stops at it, so its handles line up with the image.
+    fn preview_pipeline(&self) -> Vec<ImageOp> {
+        let pipeline = &self.img_editor.pipeline;
+        match self.canvas_op_index() {
+            Some(index) if CanvasTools::shows_input(&pipeline[index].effect) => {
+                pipeline[..index].to_vec()
+            }
+            Some(index) => pipeline[..=index].to_vec(),
+            None => pipeline.clone(),
+        }
+    }
+
+    /// Histograms to take with the next render: the inputs of ops whose
+    /// editor shows one, and whatever the histogram panel shows. The final
+    /// image's is left out while the preview stops short of it.
+    fn histogram_requests(&self) -> Vec<HistogramAt> {
+        let mut requests: Vec<HistogramAt> = self
+            .img_editor
+            .pipeline
+            .iter()
+            .filter(|op| matches!(op.effect, EffectType::Curves { .. }))
+            .map(|op| HistogramAt::Input(op.id))
+            .collect();
+        let source = self.histogram_panel_source();
+        if self.show_histogram && (source != HistogramAt::Final || self.preview_note().is_none()) {
+            requests.push(source);
+        }
+        requests
+    }
+
+    fn histogram_panel_source(&self) -> HistogramAt {
+        match self.histogram_source {
+            Some(id) => HistogramAt::Output(id),
+            None => HistogramAt::Final,
+        }
+    }
+
+    fn show_histogram_panel(&mut self, ctx: &egui::Context) {
+        egui::SidePanel::right("histogram_panel").show(ctx, |ui| {
+            ui.heading("Histogram");
+            ui.separator();
+
+            let pipeline = &self.img_editor.pipeline;
+            let step_name = |id: usize| {
+                let index = pipeline.iter().position(|op| op.id == id)?;
+                Some(format!(
+                    "After #{} {}",
+                    index + 1,
+                    pipeline[index].effect.name()
+                ))
+            };
+            // Fall back to the final image once the op is gone
+            let selected = self.histogram_source.and_then(step_name);
+            if selected.is_none() {
+                self.histogram_source = None;
+            }
+            let mut source = self.histogram_source;
+            egui::ComboBox::from_id_salt("histogram_source")
+                .selected_text(selected.unwrap_or_else(|| "Final image".to_string()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut source, None, "Final image");
+                    for op in pipeline {
+                        let name = step_name(op.id).unwrap_or_default();
+                        ui.selectable_value(&mut source, Some(op.id), name);
+                    }
+                });
+            if source != self.histogram_source {
+                self.histogram_source = source;
+                self.dirty = true;
+            }
+
+            match self.histograms.get(&self.histogram_panel_source()) {
+                Some(histogram) => histogram_view(ui, histogram),
+                None => {
+                    ui.weak("Not in the current preview");
+                }
+            }
+
+            ui.separator();
+            if ui
+                .checkbox(&mut self.show_clipping, "Show clipped pixels")
+                .changed()
+            {
+                self.dirty = true;
+            }
+        });
+    }
+
+    /// Shows the handles of the op selected for canvas editing and records
+    /// the edits they make.
+    fn show_canvas_tools(&mut self, ui: &mut egui::Ui) {
+        let Some(index) = self.canvas_op_index() else {
+            self.canvas_op = None;
+            return;
+        };
+        let [width, height] = self.img_editor.pipeline_sizes()[index];
+        let op = &mut self.img_editor.pipeline[index];
+        let before = op.clone();
+        let image_size = egui::vec2(width as f32, height as f32);
+        if self
+            .canvas_tools
+            .show(ui, &self.viewer, &mut op.effect, image_size)
+        {
+            let interacting = ui.input(|i| i.pointer.any_down());
+            self.history.record_modify(before, op.clone(), interacting);
+            self.dirty = true;
+        }
+    }
+
+    fn show_history_panel(&mut self, ctx: &egui::Context) {
+        let mut jump_to = None;
+        egui::SidePanel::right("history_panel").show(ctx, |ui| {
+            ui.heading("History");
+            ui.separator();
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let cursor = self.history.cursor();
+                if ui.selectable_label(cursor == 0, "Start").clicked() {
+                    jump_to = Some(0);
+                }
+                for (i, command) in self.history.entries().iter().enumerate() {
+                    let position = i + 1;
+                    let mut text = egui::RichText::new(command.label());
+                    if position > cursor {
+                        // Undone entries stay listed until a new edit replaces them
+                        text = text.weak();
+                    }
+                    if ui.selectable_label(position == cursor, text).clicked() {
+                        jump_to = Some(position);
+                    }
+                }
+            });
+        });
+
+        if let Some(position) = jump_to
+            && self
+                .history
+                .jump_to(position, &mut self.img_editor.pipeline)
+        {
+            self.dirty = true;
+        }
+    }
+
+    fn open_image(&mut self, path: &Path) {
+        match self.img_editor.open(path) {
+            Ok(()) => {
+                self.error = None;
+                self.preview_image = None;
+                self.dirty = true;
+                self.fit_next_render = true;
+            }
+            Err(err) => {
+                self.error = Some(format!("Could not open {}:\n{err}", path.display()));
+            }
+        }
+    }
+
+    fn load_preset_dialog(&mut self, ctx: &egui::Context) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Effect preset", &[PRESET_EXTENSION, "json", "ron"])
+            .pick_file()
+        else {
+            return;
+        };
+        match load_preset(&path) {
+            Ok(ops) => {
+                let (missing_fonts, font_check) = mpsc::channel();
+                let pipeline = ops.clone();
+                let ctx = ctx.clone();
+                thread::Builder::new()
+                    .name("font-check".to_string())
+                    .spawn(move || {
+                        let _ = missing_fonts.send(require_fonts(&pipeline));
+                        ctx.request_repaint();
+                    })
+                    .expect("failed to spawn font check");
+                self.font_check = Some(font_check);
+                let before = self.img_editor.pipeline.clone();
+                self.img_editor.set_pipeline(ops);
+                self.history.record(EditCommand::Replace {
+                    before,
+                    after: self.img_editor.pipeline.clone(),
+                });
+                self.dirty = true;
+            }
+            Err(err) => {
+                self.error = Some(format!("Could not load preset {}:\n{err}", path.display()));
+            }
+        }
+    }
+
+    /// Reports the fonts the last loaded preset is missing once the check
+    /// is done.
+    fn poll_font_check(&mut self) {
+        let Some(font_check) = &self.font_check else {
+            return;
+        };
+        match font_check.try_recv() {
+            Ok(missing_fonts) => {
+                if !missing_fonts.is_empty() {
+                    self.error = Some(format!(
+                        "{}\nThe default font stands in.",
+                        missing_fonts.join("\n")
+                    ));
+                }
+                self.font_check = None;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => self.font_check = None,
+        }
+    }
+
+    fn save_preset_dialog(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Effect preset (JSON)", &[PRESET_EXTENSION])
+            .add_filter("Effect preset (RON)", &["ron"])
+            .set_file_name(format!("preset.{PRESET_EXTENSION}"))
+            .save_file()
+        else {
+            return;
+        };
+        let format = PresetFormat::from_path(&path);
+        if let Err(err) = save_preset(&path, &self.img_editor.pipeline, format) {
+            self.error = Some(format!("Could not save preset {}:\n{err}", path.display()));
+        }
+    }
+
+    fn show_menu_bar(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::top("menu_bar").show(ctx, |ui| {
+            egui::MenuBar::new().ui(ui, |ui| {
+                ui.menu_button("File", |ui| {
+                    if ui.button("Open…").clicked() {
+                        ui.close();
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("Images", SUPPORTED_EXTENSIONS)
+                            .pick_file()
+                        {
+                            self.open_image(&path);
+                        }
+                    }
+                    if ui.button("Export…").clicked() {
+                        ui.close();
+                        self.show_export = true;
+                    }
+                    ui.separator();
+                    if ui.button("Load Preset…").clicked() {
+                        ui.close();
+                        self.load_preset_dialog(ctx);
+                    }
+                    if ui.button("Save Preset…").clicked() {
+                        ui.close();
+                        self.save_preset_dialog();
+                    }
+                });
+                ui.menu_button("Edit", |ui| {
+                    let undo = egui::Button::new("Undo").shortcut_text(ctx.format_shortcut(&UNDO));
+                    if ui.add_enabled(self.history.can_undo(), undo).clicked() {
+                        self.undo();
+                    }
+                    let redo = egui::Button::new("Redo").shortcut_text(ctx.format_shortcut(&REDO));
+                    if ui.add_enabled(self.history.can_redo(), redo).clicked() {
+                        self.redo();
+                    }
+                });
+                ui.menu_button("View", |ui| {
+                    ui.checkbox(&mut self.show_history, "History");
+                    if ui.checkbox(&mut self.show_histogram, "Histogram").changed() {
+                        // Render again to take the histogram
+                        self.dirty = true;
+                    }
+                });
+            });
+        });
+    }
+
+    fn show_export_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_export;
+        let mut save_clicked = false;
+        let options = &mut self.export_options;
+        let exporting = &self.exporting;
+
+        egui::Window::new("Export")
+            .collapsible(false)
+            .resizable(false)
+            .open(&mut open)
+            .show(ctx, |ui| {
+                egui::Grid::new("export_options")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Format");
+                        egui::ComboBox::from_id_salt("export_format")
+                            .selected_text(options.format.name())
+                            .show_ui(ui, |ui| {
+                                for format in ExportFormat::ALL {
+                                    ui.selectable_value(&mut options.format, format, format.name());
+                                }
+                            });
+                        ui.end_row();
+
+                        match options.format {
+                            ExportFormat::Jpeg => {
+                                ui.label("Quality");
+                                ui.add(egui::Slider::new(&mut options.jpeg_quality, 1..=100));
+                                ui.end_row();
+                            }
+                            ExportFormat::Png => {
+                                ui.label("Compression");
+                                ui.add(egui::Slider::new(&mut options.png_compression, 0..=9));
+                                ui.end_row();
+                            }
+                            ExportFormat::WebP | ExportFormat::Tiff => {}
+                        }
+
+                        ui.label("Flatten alpha");
+                        ui.horizontal(|ui| {
+                            if options.format.supports_alpha() {
+                                ui.checkbox(&mut options.flatten_alpha, "");
+                            } else {
+                                ui.add_enabled(false, egui::Checkbox::new(&mut true, ""))
+                                    .on_disabled_hover_text("JPEG has no alpha channel");
+                            }
+                            ui.label("over");
+                            ui.color_edit_button_srgba(&mut options.background);
+                        });
+                        ui.end_row();
+                    });
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    save_clicked = ui
+                        .add_enabled(exporting.is_none(), egui::Button::new("Save As…"))
+                        .clicked();
+                    if let Some(path) = exporting {
+                        ui.spinner();
+                        ui.label(format!("Exporting {}…", path.display()));
+                    }
+                });
+            });
+
+        if save_clicked {
+            let format = self.export_options.format;
+            let stem = self
+                .img_editor
+                .source_path
+                .as_deref()
+                .and_then(Path::file_stem)
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("image");
+            let file_name = format!("{stem}_edited.{}", format.extensions()[0]);
+
+            if let Some(mut path) = rfd::FileDialog::new()
+                .add_filter(format.name(), format.extensions())
+                .set_file_name(file_name)
+                .save_file()
+            {
+                if path.extension().is_none() {
+                    path.set_extension(format.extensions()[0]);
+                }
+                // A typed extension such as ".jpg" wins over the selected format
+                if let Some(format) = ExportFormat::from_path(&path) {
+                    self.export_options.format = format;
+                }
+                let queued = self.render_worker.export(
+                    self.img_editor.clone(),
+                    path.clone(),
+                    self.export_options,
+                );
+                match queued {
+                    Ok(()) => self.exporting = Some(path),
+                    Err(err) => {
+                        self.error = Some(format!("Could not export {}:\n{err}", path.display()));
+                    }
+                }
+            }
+        }
+        self.show_export = open;
+    }
+
+    fn show_error(&mut self, ctx: &egui::Context) {
+        let mut open = true;
+        if let Some(error) = &self.error {
+            egui::Window::new("Error")
+                .collapsible(false)
+                .resizable(false)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                });
+        }
+        if !open {
+            self.error = None;
+        }
+    }
+
+    fn show_status_bar(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let pixel = self
+                    .viewer
+                    .pixel_at(self.display_size, ctx.pointer_hover_pos());
+                match (pixel, &self.preview_image) {
+                    (Some([x, y]), Some(img)) if x < img.width() && y < img.height() => {
+                        let [r, g, b, a] = img.get_pixel(x, y).0;
+                        ui.monospace(format!(
+                            "{x:>5}, {y:>5}   R {r:>3}  G {g:>3}  B {b:>3}  A {a:>3}"
+                        ));
+                    }
+                    (Some([x, y]), _) => {
+                        ui.monospace(format!("{x:>5}, {y:>5}"));
+                    }
+                    (None, _) => {}
+                }
+                if let Some(note) = self.preview_note() {
+                    ui.separator();
+                    ui.colored_label(ui.visuals().warn_fg_color, note);
+                }
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui.button("1:1").clicked() {
+                        self.viewer.actual_size(ctx.pixels_per_point());
+                    }
+                    if ui.button("Fit").clicked() {
+                        self.viewer.fit();
+                    }
+                    let percent = self.viewer.zoom() * ctx.pixels_per_point() * 100.0;
+                    ui.label(format!("{percent:.0}%"));
+                    ui.separator();
+
+                    let hold = ui
+                        .button("Hold for original")
+                        .on_hover_text("Or hold \\ over the image");
+                    self.hold_original = hold.is_pointer_button_down_on();
+                    egui::ComboBox::from_id_salt("compare_mode")
+                        .selected_text(self.compare.name())
+                        .show_ui(ui, |ui| {
+                            for mode in CompareMode::ALL {
+                                ui.selectable_value(&mut self.compare, mode, mode.name());
+                            }
+                        });
+                    ui.label("Compare:");
+                    ui.separator();
+                    ui.label(format!(
+                        "{} × {}",
+                        self.display_size.x as u32, self.display_size.y as u32
+                    ));
+                });
+            });
+        });
+    }
+
+    fn update_original_texture(&mut self, ctx: &egui::Context) {
+        let original = &self.img_editor.original_image;
+        if self
+            .original_texture
+            .as_ref()
+            .is_some_and(|(source, _)| Arc::ptr_eq(source, original))
+        {
+            return;
+        }
+        let texture = ctx.load_texture("original", color_image(original), texture_options());
+        self.original_texture = Some((original.clone(), texture));
+    }
+
+    /// Paints the edited image, or the comparison with the original,
+    /// into the view laid out by [`Viewer::interact`].
+    fn paint_views(&mut self, ui: &mut egui::Ui, rect: egui::Rect, show_original: bool) {
+        let original = self.original_texture.as_ref().map(|(image, texture)| {
+            (
+                texture,
+                egui::vec2(image.width() as f32, image.height() as f32),
+            )
+        });
+        let edited = if show_original {
+            original
+        } else {
+            self.display_texture
+                .as_ref()
+                .map(|texture| (texture, self.display_size))
+        };
+        let paint =
+            |pane: usize, clip: egui::Rect, view: Option<(&egui::TextureHandle, egui::Vec2)>| {
+                if let Some((texture, size)) = view {
+                    self.viewer.paint_image(ui, pane, clip, texture, size);
+                }
+            };
+        let clipping = self
+            .clipping_texture
+            .as_ref()
+            .filter(|_| !show_original)
+            .map(|texture| (texture, self.display_size));
+        let paint_edited = |pane: usize, clip: egui::Rect| {
+            paint(pane, clip, edited);
+            paint(pane, clip, clipping);
+        };
+
+        match self.compare {
+            CompareMode::Off => paint_edited(0, rect),
+            CompareMode::SideBySide => {
+                paint(0, rect, original);
+                paint_edited(1, rect);
+                let x = self.viewer.pane_rect(1).left();
+                ui.painter()
+                    .vline(x, rect.y_range(), ui.visuals().window_stroke);
+                pane_label(ui, self.viewer.pane_rect(0), "Before");
+                pane_label(ui, self.viewer.pane_rect(1), "After");
+            }
+            CompareMode::Split => {
+                let x = rect.left() + rect.width() * self.split;
+                let mut before = rect;
+                before.max.x = x;
+                let mut after = rect;
+                after.min.x = x;
+                paint(0, before, original);
+                paint_edited(0, after);
+                pane_label(ui, rect, "Before");
+
+                let handle = egui::Rect::from_center_size(
+                    egui::pos2(x, rect.center().y),
+                    egui::vec2(12.0, rect.height()),
+                );
+                let response = ui
+                    .interact(handle, ui.id().with("compare_split"), egui::Sense::drag())
+                    .on_hover_cursor(egui::CursorIcon::ResizeHorizontal);
+                if response.dragged()
+                    && let Some(pointer) = response.interact_pointer_pos()
+                {
+                    self.split = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+                }
+                let painter = ui.painter_at(rect);
+                painter.vline(
+                    x,
+                    rect.y_range(),
+                    egui::Stroke::new(2.0, egui::Color32::WHITE),
+                );
+                painter.circle(
+                    egui::pos2(x, rect.center().y),
+                    8.0,
+                    egui::Color32::WHITE,
+                    egui::Stroke::new(1.0, egui::Color32::from_black_alpha(128)),
+                );
+            }
+        }
+    }
+
+    /// Hands the current pipeline to the render worker and picks up its
+    /// latest result, finished exports and failures. The previous texture
+    /// stays up until a new one arrives.
+    ///
+    /// While the pointer is held down (dragging a slider, say) renders go
+    /// through a proxy fitted to `viewport`; the full-resolution pass follows
+    /// once the pointer is released.
+    fn update_texture(&mut self, ctx: &egui::Context, viewport: egui::Vec2) {
+        let font_errors = FontRegistry::take_file_errors();
+        if !font_errors.is_empty() {
+            self.error = Some(format!(
+                "{}\nThe default font stands in.",
+                font_errors.join("\n")
             ));
         }
+        // Watermarks may have been waiting for a font that is in now
+        let font_generation = FontRegistry::generation();
+        if self.font_generation != font_generation {
+            self.font_generation = font_generation;
+            self.dirty = true;
+        }
+        let interacting = ctx.input(|i| i.pointer.any_down());
+        if self.dirty {
+            let pixels = viewport * ctx.pixels_per_point();
+            let proxy_size =
+                interacting.then_some([(pixels.x.max(1.0)) as u32, (pixels.y.max(1.0)) as u32]);
+            self.render_worker.submit(
+                self.img_editor.original_image.clone(),
+                self.preview_pipeline(),
+                proxy_size,
+                self.histogram_requests(),
+            );
+            self.needs_full_render = interacting;
+            self.dirty = false;
+        } else if self.needs_full_render && !interacting {
+            self.render_worker.submit(
+                self.img_editor.original_image.clone(),
+                self.preview_pipeline(),
+                None,
+                self.histogram_requests(),
+            );
+            self.needs_full_render = false;
+        }
 
-        self.dirty = false;
+        for event in self.render_worker.poll() {
+            match event {
+                WorkerEvent::Rendered(result) => self.show_render_result(ctx, result),
+                WorkerEvent::Exported(path, result) => {
+                    self.exporting = None;
+                    match result {
+                        Ok(()) => self.show_export = false,
+                        Err(err) => {
+                            self.error =
+                                Some(format!("Could not export {}:\n{err}", path.display()));
+                        }
+                    }
+                }
+                WorkerEvent::Failed(err) => {
+                    self.exporting = None;
+                    self.error = Some(format!("Rendering failed:\n{err}"));
+                }
+            }
+        }
+    }
+
+    fn show_render_result(&mut self, ctx: &egui::Context, result: RenderResult) {
+        let image = &result.image;
+        self.display_size = egui::vec2(image.width() as f32, image.height() as f32) / result.scale;
+        self.display_texture =
+            Some(ctx.load_texture("display", color_image(image), texture_options()));
+        if self.fit_next_render {
+            self.viewer.fit();
+            self.fit_next_render = false;
+        }
+        self.histograms = result.histograms;
+        self.clipping_texture = self
+            .show_clipping
+            .then(|| ctx.load_texture("clipping", clipping_overlay(image), texture_options()));
+        if result.scale < 1.0 {
+            return;
+        }
+        self.preview_image = Some(result.image.clone());
+        // Renders that stop at a canvas op aren't the final image
+        if result.pipeline_key == pipeline_key(&self.img_editor.pipeline) {
+            self.img_editor
+                .set_final_image(result.image, result.pipeline_key);
+        }
     }
 }
 
@@ -44,40 +853,143 @@ impl eframe::App for ImageEditorUi {
         //         ui.label("I am a floating window");
         //     });
 
+        self.handle_shortcuts(ctx);
+        self.show_menu_bar(ctx);
+        self.show_export_window(ctx);
+        self.poll_font_check();
+        self.show_error(ctx);
+        if self.show_history {
+            self.show_history_panel(ctx);
+        }
+        if self.show_histogram {
+            self.show_histogram_panel(ctx);
+        }
+
         egui::SidePanel::left("layers_panel").show(ctx, |ui| {
             ui.heading("Modifier Stack");
             ui.separator();
 
-            ui.horizontal(|ui| {
+            let sizes = self.img_editor.pipeline_sizes();
+            let [output_width, output_height] = *sizes.last().expect("never empty");
+
+            ui.horizontal_wrapped(|ui| {
                 if ui.button("+ Blur").clicked() {
-                    self.img_editor
-                        .push_new_img_op(EffectType::Blur { sigma: 2.0 });
-                    self.dirty = true;
+                    self.add_effect(EffectType::Blur { sigma: 2.0 });
                 }
                 if ui.button("+ Bright").clicked() {
-                    self.img_editor
-                        .push_new_img_op(EffectType::Brightness { value: 10 });
-                    self.dirty = true;
+                    self.add_effect(EffectType::Brightness { value: 10 });
                 }
                 if ui.button("+ Contrast").clicked() {
-                    self.img_editor
-                        .push_new_img_op(EffectType::Contrast { value: 1.2 });
-                    self.dirty = true;
+                    self.add_effect(EffectType::Contrast { value: 1.2 });
                 }
                 if ui.button("+ Text").clicked() {
-                    self.img_editor.push_new_img_op(EffectType::Watermark {
+                    self.add_effect(EffectType::Watermark {
                         params: WatermarkParams::default(),
                     });
-                    self.dirty = true;
                 }
+                if ui.button("+ Crop").clicked() {
+                    self.add_effect(EffectType::Crop {
+                        x: 0,
+                        y: 0,
+                        width: output_width,
+                        height: output_height,
+                        aspect: None,
+                    });
+                }
+                if ui.button("+ Resize").clicked() {
+                    self.add_effect(EffectType::Resize {
+                        width: output_width,
+                        height: output_height,
+                        filter: ResizeFilter::default(),
+                        keep_aspect: true,
+                    });
+                }
+                if ui.button("+ Rotate").clicked() {
+                    self.add_effect(EffectType::Rotate {
+                        degrees: 90.0,
+                        expand: true,
+                    });
+                }
+                if ui.button("+ Flip").clicked() {
+                    self.add_effect(EffectType::Flip {
+                        horizontal: true,
+                        vertical: false,
+                    });
+                }
+                ui.menu_button("+ Color", |ui| {
+                    let adjustments = [
+                        EffectType::HueShift { degrees: 30.0 },
+                        EffectType::Saturation {
+                            saturation: 0.2,
+                            vibrance: 0.0,
+                        },
+                        EffectType::WhiteBalance {
+                            temperature: 0.2,
+                            tint: 0.0,
+                        },
+                        EffectType::Gamma { gamma: 1.2 },
+                        EffectType::Exposure { stops: 0.5 },
+                        EffectType::Levels {
+                            black: 0,
+                            white: 255,
+                            mid: 1.0,
+                        },
+                        EffectType::Invert,
+                        EffectType::Grayscale,
+                        EffectType::Sepia { amount: 1.0 },
+                        EffectType::Curves {
+                            curves: CurvesParams::default(),
+                        },
+                    ];
+                    for effect in adjustments {
+                        if ui.button(effect.name()).clicked() {
+                            self.add_effect(effect);
+                            ui.close();
+                        }
+                    }
+                });
+                ui.menu_button("+ Filter", |ui| {
+                    let filters = [
+                        EffectType::UnsharpMask {
+                            amount: 1.0,
+                            radius: 2.0,
+                            threshold: 0,
+                        },
+                        EffectType::Sharpen,
+                        EffectType::Median { radius: 1 },
+                        EffectType::Bilateral {
+                            radius: 3,
+                            spatial_sigma: 3.0,
+                            color_sigma: 25.0,
+                        },
+                        EffectType::EdgeDetect {
+                            method: EdgeMethod::default(),
+                            low: 50.0,
+                            high: 100.0,
+                        },
+                        EffectType::Convolve {
+                            kernel: identity_kernel(3),
+                            size: 3,
+                            normalize: false,
+                        },
+                    ];
+                    for effect in filters {
+                        if ui.button(effect.name()).clicked() {
+                            self.add_effect(effect);
+                            ui.close();
+                        }
+                    }
+                });
             });
 
             ui.separator();
 
-            let half_width = (self.img_editor.original_image.width() / 2) as i32;
-            let half_height = (self.img_editor.original_image.height() / 2) as i32;
-
             let mut remove_index: Option<usize> = None;
+            let mut duplicate_index: Option<usize> = None;
+            let mut modified = vec![];
+            let mut canvas_op = self.canvas_op;
+            let order_before: Vec<usize> =
+                self.img_editor.pipeline.iter().map(|op| op.id).collect();
 
             let response = dnd(ui, "effect_dnd").show_vec(
                 &mut self.img_editor.pipeline,
@@ -86,124 +998,740 @@ impl eframe::App for ImageEditorUi {
                         handle.ui(ui, |ui| {
                             ui.label("::");
                         });
-                        // if ui.button("❌").clicked() {
-                        //     // state.index gives us the current position of this item in the vector
-                        //     remove_index = Some(state.index);
-                        // }
-
-                        match &mut item.effect {
-                            EffectType::Blur { sigma } => {
-                                ui.label("Blur");
-                                if ui.add(egui::Slider::new(sigma, 0.0..=10.0)).changed() {
-                                    self.dirty = true;
-                                }
-                            }
-                            EffectType::Brightness { value } => {
-                                ui.label("Bright");
-                                if ui.add(egui::Slider::new(value, -100..=100)).changed() {
-                                    self.dirty = true;
-                                }
-                            }
-                            EffectType::Contrast { value } => {
-                                ui.label("Contrast");
-                                if ui.add(egui::Slider::new(value, 0.0..=5.0)).changed() {
-                                    self.dirty = true;
-                                }
-                            }
-                            EffectType::Watermark { params } => {
-                                // if ui.button("-").clicked() {
-                                //     remove_index = Some(item.id);
-                                // }
-                                ui.vertical(|ui| {
-                                    ui.horizontal(|ui| {
-                                        ui.label("Color");
-                                        if ui.color_edit_button_srgba(&mut params.color).changed() {
-                                            self.dirty = true;
-                                        }
-                                        ui.label("Scale");
-                                        if ui
-                                            .add(egui::Slider::new(&mut params.scale, 1.0..=100.0))
-                                            .changed()
-                                        {
-                                            self.dirty = true;
-                                        }
-                                    });
-                                    ui.horizontal(|ui| {
-                                        // ui.label("Text");
-                                        if ui
-                                            .add(egui::TextEdit::multiline(&mut params.text))
-                                            .changed()
-                                        {
-                                            self.dirty = true;
-                                        }
-                                    });
-                                    ui.horizontal(|ui| {
-                                        ui.label("Angle");
-                                        if ui
-                                            .add(egui::Slider::new(
-                                                &mut params.degree,
-                                                -180.0..=180.0,
-                                            ))
-                                            .changed()
-                                        {
-                                            self.dirty = true;
-                                        }
-                                    });
-                                    ui.horizontal(|ui| {
-                                        ui.label("X");
-                                        if ui
-                                            .add(egui::Slider::new(
-                                                &mut params.x,
-                                                -half_width..=half_width,
-                                            ))
-                                            .changed()
-                                        {
-                                            self.dirty = true;
-                                        }
-                                    });
-                                    ui.horizontal(|ui| {
-                                        ui.label("Y");
-                                        if ui
-                                            .add(egui::Slider::new(
-                                                &mut params.y,
-                                                -half_height..=half_height as i32,
-                                            ))
-                                            .changed()
-                                        {
-                                            self.dirty = true;
-                                        }
-                                    });
-                                });
+                        if ui.button("❌").on_hover_text("Remove").clicked() {
+                            // state.index gives us the current position of this item in the vector
+                            remove_index = Some(state.index);
+                        }
+                        if ui.button("⧉").on_hover_text("Duplicate").clicked() {
+                            duplicate_index = Some(state.index);
+                        }
+                        if CanvasTools::supports(&item.effect) {
+                            let selected = canvas_op == Some(item.id);
+                            if ui
+                                .selectable_label(selected, "✥")
+                                .on_hover_text("Edit on canvas (Esc to finish)")
+                                .clicked()
+                            {
+                                canvas_op = (!selected).then_some(item.id);
                             }
                         }
 
-                        // ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                        //     if ui.button("❌").clicked() {
-                        //         // state.index gives us the current position of this item in the vector
-                        //         remove_index = Some(state.index);
-                        //     }
-                        // });
+                        let before = item.clone();
+                        let mut changed = ui
+                            .checkbox(&mut item.enabled, "")
+                            .on_hover_text("Enabled (uncheck to bypass)")
+                            .changed();
+                        // Geometry ops change the size seen by the ops after them
+                        let input_size = sizes.get(state.index).copied().unwrap_or(sizes[0]);
+                        let histogram = self
+                            .histograms
+                            .get(&HistogramAt::Input(item.id))
+                            .map(|h| &**h);
+                        changed |= effect_editor(ui, &mut item.effect, input_size, histogram);
+                        if changed {
+                            modified.push((before, item.clone()));
+                        }
                     });
                 },
             );
 
+            // Slider drags and typing keep editing the same history entry
+            // until the pointer is released or the text field loses focus
+            let interacting = ctx.input(|i| i.pointer.any_down()) || ctx.wants_keyboard_input();
+            for (before, after) in modified {
+                self.history.record_modify(before, after, interacting);
+                self.dirty = true;
+            }
+            if !interacting {
+                self.history.end_interaction();
+            }
+
+            if canvas_op != self.canvas_op {
+                self.canvas_op = canvas_op;
+                self.dirty = true;
+            }
+
             if let Some(idx) = remove_index {
-                self.img_editor.pipeline.remove(idx);
+                let op = self.img_editor.pipeline.remove(idx);
+                self.history.record(EditCommand::Remove { index: idx, op });
                 self.dirty = true; // Tell the app to re-process the image
             }
+            if let Some(idx) = duplicate_index {
+                let index = self.img_editor.duplicate_img_op(idx);
+                self.history.record(EditCommand::Insert {
+                    index,
+                    op: self.img_editor.pipeline[index].clone(),
+                });
+                self.dirty = true;
+            }
 
-            if response.final_update().is_some() {
+            if response.is_drag_finished() {
+                if let Some(order) = self.drag_start_order.take()
+                    && let Some(command) = detect_move(&order, &self.img_editor.pipeline)
+                {
+                    self.history.record(command);
+                }
                 self.dirty = true;
+            } else if response.is_dragging() {
+                self.drag_start_order.get_or_insert(order_before);
+            } else {
+                self.drag_start_order = None;
             }
         });
 
+        self.show_status_bar(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            if self.dirty {
-                self.update_texture(ctx);
+            let dropped = ctx.input(|i| i.raw.dropped_files.first().and_then(|f| f.path.clone()));
+            if let Some(path) = dropped {
+                self.open_image(&path);
+            }
+
+            self.update_texture(ctx, ui.available_size());
+            self.update_original_texture(ctx);
+            let panes = if self.compare == CompareMode::SideBySide {
+                2
+            } else {
+                1
+            };
+            let response = self.viewer.interact(ui, self.display_size, panes);
+            if response.double_clicked() {
+                self.viewer.fit();
+            }
+            let hold_key = !ctx.wants_keyboard_input()
+                && response.hovered()
+                && ctx.input(|i| i.key_down(egui::Key::Backslash));
+            let show_original = self.hold_original || hold_key;
+            self.paint_views(ui, response.rect, show_original);
+            if self.compare == CompareMode::Off && !show_original {
+                self.show_canvas_tools(ui);
+            }
+            if self.render_worker.is_busy() {
+                let rect = ui.max_rect();
+                let spinner = egui::Rect::from_min_size(
+                    rect.right_top() + egui::vec2(-28.0, 8.0),
+                    egui::vec2(20.0, 20.0),
+                );
+                ui.put(spinner, egui::Spinner::new());
             }
-            if let Some(texture) = &self.display_texture {
-                ui.image((texture.id(), texture.size_vec2()));
+
+            if ctx.input(|i| !i.raw.hovered_files.is_empty()) {
+                let rect = ui.max_rect();
+                let painter = ui.painter();
+                painter.rect_filled(rect, 0.0, egui::Color32::from_black_alpha(160));
+                painter.text(
+                    rect.center(),
+                    egui::Align2::CENTER_CENTER,
+                    "Drop image to open",
+                    egui::FontId::proportional(24.0),
+                    egui::Color32::WHITE,
+                );
             }
         });
     }
 }
+
+/// Nearest magnification keeps pixels crisp when zoomed in.
+fn texture_options() -> egui::TextureOptions {
+    egui::TextureOptions {
+        magnification: egui::TextureFilter::Nearest,
+        ..egui::TextureOptions::LINEAR
+    }
+}
+
+fn pane_label(ui: &egui::Ui, pane: egui::Rect, text: &str) {
+    let painter = ui.painter_at(pane);
+    let galley = painter.layout_no_wrap(
+        text.to_string(),
+        egui::FontId::proportional(13.0),
+        egui::Color32::WHITE,
+    );
+    let pos = pane.left_top() + egui::vec2(8.0, 8.0);
+    painter.rect_filled(
+        egui::Rect::from_min_size(pos, galley.size()).expand(4.0),
+        4.0,
+        egui::Color32::from_black_alpha(140),
+    );
+    painter.galley(pos, galley, egui::Color32::WHITE);
+}
+
+fn color_image(img: &DynamicImage) -> egui::ColorImage {
+    let size = [img.width() as usize, img.height() as usize];
+    match img.as_rgba8() {
+        Some(rgba) => egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_raw()),
+        None => egui::ColorImage::from_rgba_unmultiplied(size, img.to_rgba8().as_raw()),
+    }
+}
+
+/// Works out the single move that turned the op ids in `order` into the
+/// current `pipeline` order.
+fn detect_move(order: &[usize], pipeline: &[ImageOp]) -> Option<EditCommand> {
+    let differs = |i: &usize| pipeline.get(*i).map(|op| op.id) != order.get(*i).copied();
+    let first = (0..order.len()).find(differs)?;
+    let last = (0..order.len()).rfind(differs)?;
+    if pipeline[last].id == order[first] {
+        Some(EditCommand::Move {
+            from: first,
+            to: last,
+        })
+    } else {
+        Some(EditCommand::Move {
+            from: last,
+            to: first,
+        })
+    }
+}
+
+/// Shows the parameter widgets of one effect. Returns whether anything changed.
+///
+/// `input_size` is the size of the image the effect receives, after any
+/// geometry ops before it, and `input_histogram` its histogram if one was
+/// asked for.
+fn effect_editor(
+    ui: &mut egui::Ui,
+    effect: &mut EffectType,
+    input_size: [u32; 2],
+    input_histogram: Option<&Histogram>,
+) -> bool {
+    let [input_width, input_height] = input_size;
+    let half_width = (input_width / 2) as i32;
+    let half_height = (input_height / 2) as i32;
+    let mut changed = false;
+    match effect {
+        EffectType::Blur { sigma } => {
+            ui.label("Blur");
+            changed |= ui.add(egui::Slider::new(sigma, 0.0..=10.0)).changed();
+        }
+        EffectType::Brightness { value } => {
+            ui.label("Bright");
+            changed |= ui.add(egui::Slider::new(value, -100..=100)).changed();
+        }
+        EffectType::Contrast { value } => {
+            ui.label("Contrast");
+            changed |= ui.add(egui::Slider::new(value, 0.0..=5.0)).changed();
+        }
+        EffectType::Watermark { params } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Color");
+                    changed |= ui.color_edit_button_srgba(&mut params.color).changed();
+                    ui.label("Scale");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.scale, 1.0..=100.0))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    // ui.label("Text");
+                    changed |= ui
+                        .add(egui::TextEdit::multiline(&mut params.text))
+                        .changed();
+                });
+                changed |= font_picker(ui, params);
+                ui.horizontal(|ui| {
+                    for align in TextAlign::ALL {
+                        changed |= ui
+                            .selectable_value(&mut params.align, align, align.name())
+                            .changed();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Line");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.line_height, 0.5..=3.0).suffix("×"))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Letter");
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(&mut params.letter_spacing, -0.2..=1.0).suffix(" em"),
+                        )
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Wrap");
+                    let width = input_width.max(1) as f32;
+                    // Switching units keeps the wrap at the same place
+                    let current = params.wrap_width.pixels(input_width);
+                    let options = [
+                        ("Off", WrapWidth::Off),
+                        (
+                            "px",
+                            WrapWidth::Pixels(current.unwrap_or(width / 2.0).round()),
+                        ),
+                        (
+                            "%",
+                            WrapWidth::Percent(
+                                current.map_or(50.0, |px| px / width * 100.0).round(),
+                            ),
+                        ),
+                    ];
+                    for (name, option) in options {
+                        let selected = std::mem::discriminant(&params.wrap_width)
+                            == std::mem::discriminant(&option);
+                        if ui.selectable_label(selected, name).clicked() && !selected {
+                            params.wrap_width = option;
+                            changed = true;
+                        }
+                    }
+                    match &mut params.wrap_width {
+                        WrapWidth::Off => {}
+                        WrapWidth::Pixels(px) => {
+                            changed |= ui
+                                .add(egui::DragValue::new(px).range(1.0..=width).suffix(" px"))
+                                .changed();
+                        }
+                        WrapWidth::Percent(percent) => {
+                            changed |= ui
+                                .add(egui::DragValue::new(percent).range(1.0..=100.0).suffix("%"))
+                                .changed();
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Angle");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.degree, -180.0..=180.0))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    changed |= ui.checkbox(&mut params.tiled, "Tile").changed();
+                    if params.tiled {
+                        ui.label("Gap");
+                        for spacing in &mut params.tile_spacing {
+                            changed |= ui
+                                .add(egui::DragValue::new(spacing).range(0.0..=2000.0))
+                                .changed();
+                        }
+                    }
+                });
+                if params.tiled {
+                    ui.horizontal(|ui| {
+                        ui.label("Stagger");
+                        changed |= ui
+                            .add(egui::Slider::new(&mut params.tile_stagger, 0.0..=1.0))
+                            .changed();
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Opacity");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.opacity, 0.0..=1.0))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Outline");
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut params.outline_width)
+                                .range(0.0..=50.0)
+                                .speed(0.1)
+                                .suffix(" px"),
+                        )
+                        .changed();
+                    changed |= ui
+                        .color_edit_button_srgba(&mut params.outline_color)
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    changed |= ui.checkbox(&mut params.shadow, "Shadow").changed();
+                    changed |= ui
+                        .color_edit_button_srgba(&mut params.shadow_color)
+                        .changed();
+                });
+                if params.shadow {
+                    ui.horizontal(|ui| {
+                        ui.label("Offset");
+                        for offset in &mut params.shadow_offset {
+                            changed |= ui
+                                .add(egui::DragValue::new(offset).range(-200.0..=200.0))
+                                .changed();
+                        }
+                        ui.label("Blur");
+                        changed |= ui
+                            .add(
+                                egui::DragValue::new(&mut params.shadow_blur)
+                                    .range(0.0..=50.0)
+                                    .speed(0.1),
+                            )
+                            .changed();
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Blend");
+                    egui::ComboBox::from_id_salt(ui.id().with("blend_mode"))
+                        .selected_text(params.blend_mode.name())
+                        .show_ui(ui, |ui| {
+                            for mode in BlendMode::ALL {
+                                changed |= ui
+                                    .selectable_value(&mut params.blend_mode, mode, mode.name())
+                                    .changed();
+                            }
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("X");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.x, -half_width..=half_width))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Y");
+                    changed |= ui
+                        .add(egui::Slider::new(&mut params.y, -half_height..=half_height))
+                        .changed();
+                });
+            });
+        }
+        EffectType::Crop {
+            x,
+            y,
+            width,
+            height,
+            aspect,
+        } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Crop");
+                    let selected = match *aspect {
+                        None => "Free".to_string(),
+                        Some([w, h]) => format!("{w}:{h}"),
+                    };
+                    egui::ComboBox::from_id_salt(ui.id().with("crop_aspect"))
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            for (name, ratio) in CROP_ASPECTS {
+                                changed |= ui.selectable_value(aspect, ratio, name).changed();
+                            }
+                            let original = reduce_ratio(input_size);
+                            let name = format!("Input ({}:{})", original[0], original[1]);
+                            changed |= ui.selectable_value(aspect, Some(original), name).changed();
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("X");
+                    changed |= ui
+                        .add(egui::DragValue::new(x).range(0..=input_width - 1))
+                        .changed();
+                    ui.label("Y");
+                    changed |= ui
+                        .add(egui::DragValue::new(y).range(0..=input_height - 1))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("W");
+                    let width_changed = ui
+                        .add(egui::DragValue::new(width).range(1..=input_width))
+                        .changed();
+                    ui.label("H");
+                    let height_changed = ui
+                        .add(egui::DragValue::new(height).range(1..=input_height))
+                        .changed();
+                    changed |= width_changed || height_changed;
+                    if changed && let Some(ratio) = *aspect {
+                        let max = [
+                            input_width - (*x).min(input_width - 1),
+                            input_height - (*y).min(input_height - 1),
+                        ];
+                        [*width, *height] =
+                            fit_aspect([*width, *height], ratio, height_changed, max);
+                    }
+                });
+            });
+        }
+        EffectType::Resize {
+            width,
+            height,
+            filter,
+            keep_aspect,
+        } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Resize");
+                    egui::ComboBox::from_id_salt(ui.id().with("resize_filter"))
+                        .selected_text(filter.name())
+                        .show_ui(ui, |ui| {
+                            for option in ResizeFilter::ALL {
+                                changed |=
+                                    ui.selectable_value(filter, option, option.name()).changed();
+                            }
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("W");
+                    let width_changed = ui
+                        .add(egui::DragValue::new(width).range(1..=16384))
+                        .changed();
+                    ui.label("H");
+                    let height_changed = ui
+                        .add(egui::DragValue::new(height).range(1..=16384))
+                        .changed();
+                    let aspect_changed = ui.checkbox(keep_aspect, "Keep aspect").changed();
+                    changed |= width_changed || height_changed || aspect_changed;
+                    if changed && *keep_aspect {
+                        [*width, *height] = fit_aspect(
+                            [*width, *height],
+                            input_size,
+                            height_changed,
+                            [16384, 16384],
+                        );
+                    }
+                });
+            });
+        }
+        EffectType::Rotate { degrees, expand } => {
+            ui.label("Rotate");
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    changed |= ui
+                        .add(egui::Slider::new(degrees, -180.0..=180.0).suffix("°"))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    for (label, turn) in [("⟲ 90°", -90.0), ("⟳ 90°", 90.0)] {
+                        if ui.button(label).clicked() {
+                            // Keep the angle within the slider's range
+                            *degrees = (*degrees + turn + 180.0).rem_euclid(360.0) - 180.0;
+                            changed = true;
+                        }
+                    }
+                    changed |= ui.checkbox(expand, "Expand canvas").changed();
+                });
+            });
+        }
+        EffectType::Flip {
+            horizontal,
+            vertical,
+        } => {
+            ui.label("Flip");
+            changed |= ui.checkbox(horizontal, "Horizontal").changed();
+            changed |= ui.checkbox(vertical, "Vertical").changed();
+        }
+        EffectType::HueShift { degrees } => {
+            ui.label("Hue");
+            changed |= ui
+                .add(egui::Slider::new(degrees, -180.0..=180.0).suffix("°"))
+                .changed();
+        }
+        EffectType::Saturation {
+            saturation,
+            vibrance,
+        } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Saturation");
+                    changed |= ui.add(egui::Slider::new(saturation, -1.0..=1.0)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Vibrance");
+                    changed |= ui.add(egui::Slider::new(vibrance, -1.0..=1.0)).changed();
+                });
+            });
+        }
+        EffectType::WhiteBalance { temperature, tint } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Temperature");
+                    changed |= ui.add(egui::Slider::new(temperature, -1.0..=1.0)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Tint");
+                    changed |= ui.add(egui::Slider::new(tint, -1.0..=1.0)).changed();
+                });
+            });
+        }
+        EffectType::Gamma { gamma } => {
+            ui.label("Gamma");
+            changed |= ui
+                .add(egui::Slider::new(gamma, 0.1..=5.0).logarithmic(true))
+                .changed();
+        }
+        EffectType::Exposure { stops } => {
+            ui.label("Exposure");
+            changed |= ui
+                .add(egui::Slider::new(stops, -5.0..=5.0).suffix(" EV"))
+                .changed();
+        }
+        EffectType::Levels { black, white, mid } => {
+            ui.vertical(|ui| {
+                ui.label("Levels");
+                ui.horizontal(|ui| {
+                    ui.label("Black");
+                    changed |= ui.add(egui::Slider::new(black, 0..=254)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("White");
+                    changed |= ui.add(egui::Slider::new(white, 1..=255)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Mid");
+                    changed |= ui
+                        .add(egui::Slider::new(mid, 0.1..=10.0).logarithmic(true))
+                        .changed();
+                });
+                // Keep the points in order
+                if *white <= *black {
+                    *white = *black + 1;
+                }
+            });
+        }
+        EffectType::Invert => {
+            ui.label("Invert");
+        }
+        EffectType::Grayscale => {
+            ui.label("Grayscale");
+        }
+        EffectType::Sepia { amount } => {
+            ui.label("Sepia");
+            changed |= ui.add(egui::Slider::new(amount, 0.0..=1.0)).changed();
+        }
+        EffectType::Curves { curves } => {
+            changed |= curves_editor(ui, curves, input_histogram);
+        }
+        EffectType::UnsharpMask {
+            amount,
+            radius,
+            threshold,
+        } => {
+            ui.vertical(|ui| {
+                ui.label("Unsharp Mask");
+                ui.horizontal(|ui| {
+                    ui.label("Amount");
+                    changed |= ui.add(egui::Slider::new(amount, 0.0..=5.0)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Radius");
+                    changed |= ui.add(egui::Slider::new(radius, 0.1..=20.0)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Threshold");
+                    changed |= ui.add(egui::Slider::new(threshold, 0..=255)).changed();
+                });
+            });
+        }
+        EffectType::Sharpen => {
+            ui.label("Sharpen 3x3");
+        }
+        EffectType::Median { radius } => {
+            ui.label("Median");
+            changed |= ui.add(egui::Slider::new(radius, 0..=10)).changed();
+        }
+        EffectType::Bilateral {
+            radius,
+            spatial_sigma,
+            color_sigma,
+        } => {
+            ui.vertical(|ui| {
+                ui.label("Bilateral");
+                ui.horizontal(|ui| {
+                    ui.label("Radius");
+                    changed |= ui.add(egui::Slider::new(radius, 1..=10)).changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Spatial σ");
+                    changed |= ui
+                        .add(egui::Slider::new(spatial_sigma, 0.5..=20.0))
+                        .changed();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Color σ");
+                    changed |= ui
+                        .add(egui::Slider::new(color_sigma, 1.0..=255.0))
+                        .changed();
+                });
+            });
+        }
+        EffectType::EdgeDetect { method, low, high } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Edges");
+                    for option in EdgeMethod::ALL {
+                        changed |= ui.selectable_value(method, option, option.name()).changed();
+                    }
+                });
+                if *method == EdgeMethod::Canny {
+                    ui.horizontal(|ui| {
+                        ui.label("Low");
+                        changed |= ui.add(egui::Slider::new(low, 0.0..=500.0)).changed();
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("High");
+                        changed |= ui.add(egui::Slider::new(high, 0.0..=500.0)).changed();
+                    });
+                    // Keep the thresholds in order
+                    if *high < *low {
+                        *high = *low;
+                    }
+                }
+            });
+        }
+        EffectType::Convolve {
+            kernel,
+            size,
+            normalize,
+        } => {
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Kernel");
+                    for option in [3, 5, 7] {
+                        if ui
+                            .selectable_label(*size == option, format!("{option}x{option}"))
+                            .clicked()
+                            && *size != option
+                        {
+                            *size = option;
+                            *kernel = identity_kernel(option);
+                            changed = true;
+                        }
+                    }
+                    changed |= ui.checkbox(normalize, "Normalize").changed();
+                });
+                if kernel.len() != (*size * *size) as usize {
+                    *kernel = identity_kernel(*size);
+                    changed = true;
+                }
+                egui::Grid::new(ui.id().with("kernel"))
+                    .spacing([2.0, 2.0])
+                    .show(ui, |ui| {
+                        for row in kernel.chunks_mut(*size as usize) {
+                            for weight in row {
+                                changed |= ui
+                                    .add(egui::DragValue::new(weight).speed(0.1).max_decimals(3))
+                                    .changed();
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+        }
+    }
+    changed
+}
+
+/// Adjusts `size` to the width:height `ratio`, deriving the width from the
+/// height when `from_height` is set and the height from the width otherwise,
+/// then shrinks it to fit within `max`.
+fn fit_aspect(size: [u32; 2], ratio: [u32; 2], from_height: bool, max: [u32; 2]) -> [u32; 2] {
+    let ratio = ratio[0] as f64 / ratio[1].max(1) as f64;
+    let [mut width, mut height] = size.map(f64::from);
+    if from_height {
+        width = height * ratio;
+    } else {
+        height = width / ratio;
+    }
+    let shrink = (max[0] as f64 / width).min(max[1] as f64 / height).min(1.0);
+    [
+        ((width * shrink).round() as u32).max(1),
+        ((height * shrink).round() as u32).max(1),
+    ]
+}
+
+/// `size` as the smallest whole-number ratio, e.g. 1920x1080 as 16:9.
+fn reduce_ratio(size: [u32; 2]) -> [u32; 2] {
+    let (mut a, mut b) = (size[0], size[1]);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let gcd = a.max(1);
+    [size[0] / gcd, size[1] / gcd]
+}
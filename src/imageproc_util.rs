@@ -1,196 +1,388 @@
-use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
-use image::{DynamicImage, GenericImage, GenericImageView, Pixel, Rgba, RgbaImage};
+use ab_glyph::{Font, FontVec, Glyph, PxScale, PxScaleFont, ScaleFont, point};
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage, imageops::overlay};
 use imageproc::{
-    drawing::{draw_filled_rect_mut, draw_text_mut},
+    drawing::draw_filled_rect_mut,
+    filter::gaussian_blur_f32,
+    geometric_transformations::{Interpolation, rotate_about_center_no_crop},
+    morphology::{Mask, grayscale_dilate},
     rect::Rect,
 };
-use std::f32::consts::PI;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    f32::consts::PI,
+    sync::{Arc, LazyLock, Mutex, PoisonError},
+};
+
+use crate::{
+    blend_util::{BlendMode, blend_layer},
+    font_registry::{FontFace, FontRegistry},
+    font_util::{TextLayout, glyph_positions, layout_lines, measure_multiline_text},
+    image_editor::{WatermarkParams, WrapWidth},
+    pipeline_cache::serialized_key,
+};
+
+/// Smallest distance in pixels between tiles of a tiled watermark.
+const MIN_TILE_PITCH: f32 = 8.0;
+/// Bytes of rasterized watermark layers kept. The newest layer stays even
+/// if it is bigger, so moving a watermark on a large image stays fast.
+const LAYER_CACHE_BUDGET: usize = 128 * 1024 * 1024;
+
+/// Recently rasterized watermark layers by [`layer_key`], so moving a
+/// watermark only composites it again.
+static LAYER_CACHE: LazyLock<Mutex<VecDeque<CachedLayer>>> = LazyLock::new(Default::default);
 
-use crate::{font_util::calculate_line_width, image_editor::WatermarkParams};
+type CachedLayer = (u64, Arc<RgbaImage>);
 
+/// Draws `text` laid out per `layout`, with the block's top edge at `y` and
+/// its horizontal center at `x`. Returns the block's width and height.
 pub fn draw_multiline_text_mut(
     image: &mut RgbaImage,
     color: Rgba<u8>,
     x: i32,
     y: i32,
-    scale: PxScale,
-    font: &FontVec,
+    scaled_font: PxScaleFont<&FontVec>,
     text: &str,
+    layout: &TextLayout,
 ) -> (f32, f32) {
-    let scaled_font = font.as_scaled(scale);
-    let line_height = scaled_font.height();
-    let mut width = 0f32;
-    let mut height = 0f32;
-
-    for line in text.lines() {
-        if !line.is_empty() {
-            let w = calculate_line_width(scaled_font, line);
-            width = width.max(w);
-            draw_text_mut(
+    let (lines, (width, height)) = layout_lines(scaled_font, text, layout);
+    let left = x as f32 - width / 2.0;
+    let baseline = y as f32 + scaled_font.ascent();
+
+    for line in lines {
+        let (glyphs, _) = glyph_positions(scaled_font, line.text, layout.letter_spacing);
+        let mut stretch = 0.0;
+        for ((glyph_id, pen), c) in glyphs.into_iter().zip(line.text.chars()) {
+            let position = point(left + line.x + pen + stretch, baseline + line.y);
+            draw_glyph(
                 image,
                 color,
-                x - ((w as i32) / 2),
-                y + height as i32,
-                scale,
-                font,
-                line,
+                scaled_font,
+                glyph_id.with_scale_and_position(scaled_font.scale(), position),
             );
+            if c.is_whitespace() {
+                stretch += line.stretch;
+            }
         }
-        height += line_height;
     }
     (width, height)
 }
 
+/// Blends the coverage of `glyph` in `color` into `image`.
+fn draw_glyph(
+    image: &mut RgbaImage,
+    color: Rgba<u8>,
+    scaled_font: PxScaleFont<&FontVec>,
+    glyph: Glyph,
+) {
+    let Some(outlined) = scaled_font.outline_glyph(glyph) else {
+        return;
+    };
+    let bounds = outlined.px_bounds();
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    outlined.draw(|gx, gy, coverage| {
+        let x = bounds.min.x as i32 + gx as i32;
+        let y = bounds.min.y as i32 + gy as i32;
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return;
+        }
+        let coverage = coverage.clamp(0.0, 1.0);
+        let pixel = image.get_pixel_mut(x as u32, y as u32);
+        for c in 0..4 {
+            let mixed = pixel[c] as f32 * (1.0 - coverage) + color[c] as f32 * coverage;
+            pixel[c] = mixed.round() as u8;
+        }
+    });
+}
+
+/// The face `params` asks for and its font from the shared cache. A family
+/// that isn't registered yet is loaded from the watermark's font file; if
+/// that fails too, the default family stands in and the failure is left for
+/// [`FontRegistry::take_file_errors`].
+fn watermark_font(params: &WatermarkParams) -> Result<(FontFace, Arc<FontVec>), Box<dyn Error>> {
+    if let Some(path) = &params.font_path
+        && !FontRegistry::get().has_family(&params.font_family)
+    {
+        let _ = FontRegistry::require_file(path);
+    }
+    let face = FontRegistry::get()
+        .find(&params.font_family, params.weight, params.italic)
+        .clone();
+    let font = face.font(params.weight, &params.variations)?;
+    Ok((face, font))
+}
+
+/// Width and height of the watermark's text block before rotation.
+pub(crate) fn watermark_text_size(
+    params: &WatermarkParams,
+    image_width: u32,
+) -> Option<(f32, f32)> {
+    let (_, font) = watermark_font(params).ok()?;
+    Some(text_size(params, &font, image_width))
+}
+
+fn text_size(params: &WatermarkParams, font: &FontVec, image_width: u32) -> (f32, f32) {
+    let scale = PxScale::from(params.scale);
+    let layout = params.text_layout(image_width);
+    measure_multiline_text(font, scale, &params.text, &layout)
+}
+
 pub(crate) fn draw_watermark(
     image: &mut DynamicImage,
     params: &WatermarkParams,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let scale = PxScale::from(params.scale);
-    // let font_vec = Vec::from(include_bytes!("../DejaVuSans.ttf") as &[u8]);
-    let font_vec = Vec::from(include_bytes!("../Roboto-VariableFont_wdth,wght.ttf") as &[u8]);
-    let font = FontVec::try_from_vec(font_vec)?;
-    let mut text_image: image::ImageBuffer<Rgba<u8>, Vec<u8>> =
-        image::ImageBuffer::new(image.width(), image.height());
+) -> Result<(), Box<dyn Error>> {
+    let (face, font) = watermark_font(params)?;
+    let size = [image.width(), image.height()];
+    if params.tiled {
+        // A tile only depends on the image size through a relative wrap width
+        let relative = matches!(params.wrap_width, WrapWidth::Percent(_));
+        let tile = cached_layer(layer_key(params, &face, relative.then_some(size)), || {
+            tile_image(params, &font, size[0])
+        });
+        let layer = tiled_layer(&tile, size, params, &font);
+        blend_layer(image, &layer, 0, 0, params.blend_mode, params.opacity);
+        return Ok(());
+    }
+    let layer = cached_layer(layer_key(params, &face, Some(size)), || {
+        text_layer(params, &font, size)
+    });
+    blend_layer(
+        image,
+        &layer,
+        params.x as i64,
+        params.y as i64,
+        params.blend_mode,
+        params.opacity,
+    );
 
-    let color = Rgba(params.color.to_array());
-    let x = (image.width() / 2) as i32;
-    let y = (image.height() / 2) as i32;
+    Ok(())
+}
 
-    draw_filled_rect_mut(
+/// Identifies the rasterized text of `params` drawn with `face`, on an image
+/// of `size` if the layer depends on it. Leaves out what is applied after
+/// rasterizing.
+fn layer_key(params: &WatermarkParams, face: &FontFace, size: Option<[u32; 2]>) -> u64 {
+    let params = WatermarkParams {
+        x: 0,
+        y: 0,
+        blend_mode: BlendMode::Normal,
+        opacity: 1.0,
+        ..params.clone()
+    };
+    serialized_key(&(
+        params,
+        &face.source,
+        face.index,
+        size,
+        FontRegistry::generation(),
+    ))
+}
+
+/// The layer cached under `key`, rendering and caching it if missing. The
+/// least recently used layers make room once over [`LAYER_CACHE_BUDGET`].
+fn cached_layer(key: u64, render: impl FnOnce() -> RgbaImage) -> Arc<RgbaImage> {
+    {
+        let mut cache = LAYER_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = cache.iter().position(|(k, _)| *k == key) {
+            let entry = cache.remove(index).expect("index is in range");
+            let layer = entry.1.clone();
+            cache.push_back(entry);
+            return layer;
+        }
+    }
+    // Render unlocked so other watermarks aren't held up
+    let layer = Arc::new(render());
+    let mut cache = LAYER_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+    cache.push_back((key, layer.clone()));
+    let mut bytes: usize = cache.iter().map(|(_, layer)| layer.as_raw().len()).sum();
+    while bytes > LAYER_CACHE_BUDGET && cache.len() > 1 {
+        let (_, evicted) = cache.pop_front().expect("more than one layer");
+        bytes -= evicted.as_raw().len();
+    }
+    layer
+}
+
+/// The text and its guide lines on a transparent layer of `size`, rotated
+/// about its center.
+fn text_layer(params: &WatermarkParams, font: &FontVec, [width, height]: [u32; 2]) -> RgbaImage {
+    let scaled_font = font.as_scaled(params.scale);
+    let mut text_image = RgbaImage::new(width, height);
+
+    let color = Rgba(params.color.to_srgba_unmultiplied());
+    let x = (width / 2) as i32;
+    let y = (height / 2) as i32;
+
+    draw_filled_rect_mut(&mut text_image, Rect::at(0, y).of_size(width, 2), color);
+    let (_w, h) = draw_multiline_text_mut(
         &mut text_image,
-        Rect::at(0, y).of_size(image.width(), 2),
         color,
+        x,
+        y,
+        scaled_font,
+        &params.text,
+        &params.text_layout(width),
     );
-    let (_w, h) = draw_multiline_text_mut(&mut text_image, color, x, y, scale, &font, &params.text);
 
     draw_filled_rect_mut(
         &mut text_image,
-        Rect::at(0, y + h as i32).of_size(image.width(), 2),
+        Rect::at(0, y + h as i32).of_size(width, 2),
         color,
     );
+    text_image = decorate(&text_image, params);
+
     let theta = params.degree * (PI / 180.0);
-    text_image = imageproc::geometric_transformations::rotate_about_center(
+    imageproc::geometric_transformations::rotate_about_center(
         &text_image,
         theta,
         imageproc::geometric_transformations::Interpolation::Bicubic,
         Rgba([0, 0, 0, 0]),
-    );
-    // image::imageops::overlay(image, &text_image, 0, -100);
-    image::imageops::overlay(image, &text_image, params.x as i64, params.y as i64);
-    // blend_exclusion2(image, &text_image, params.x as i64, params.y as i64);
+    )
+}
 
-    Ok(())
+/// One tile of a tiled watermark: the text block alone, decorated and
+/// rotated.
+fn tile_image(params: &WatermarkParams, font: &FontVec, image_width: u32) -> RgbaImage {
+    let (text_width, text_height) = text_size(params, font, image_width);
+    let pad = decoration_padding(params);
+    let mut block = RgbaImage::new(
+        (text_width + 2.0 * pad).ceil() as u32,
+        (text_height + 2.0 * pad).ceil() as u32,
+    );
+    let color = Rgba(params.color.to_srgba_unmultiplied());
+    let x = (pad + text_width / 2.0) as i32;
+    draw_multiline_text_mut(
+        &mut block,
+        color,
+        x,
+        pad as i32,
+        font.as_scaled(params.scale),
+        &params.text,
+        &params.text_layout(image_width),
+    );
+    rotate_about_center_no_crop(
+        &decorate(&block, params),
+        params.degree.to_radians(),
+        Interpolation::Bicubic,
+        Rgba([0, 0, 0, 0]),
+    )
 }
 
-fn _blend_difference(
-    background: &mut image::DynamicImage,
-    text_image: &image::DynamicImage,
-    offset_x: i64,
-    offset_y: i64,
-) {
-    let (text_w, text_h) = text_image.dimensions();
-    let (bg_w, bg_h) = background.dimensions();
-
-    for y in 0..text_h {
-        for x in 0..text_w {
-            let bg_x = offset_x + x as i64;
-            let bg_y = offset_y + y as i64;
-
-            // 1. Bounds check: Ensure we are inside the background image
-            if bg_x >= 0 && bg_x < bg_w as i64 && bg_y >= 0 && bg_y < bg_h as i64 {
-                let text_pixel = text_image.get_pixel(x, y);
-                let text_rgba = text_pixel.to_rgba();
-
-                // Skip completely transparent pixels to save processing
-                if text_rgba[3] == 0 {
-                    continue;
-                }
-
-                let bg_pixel = background.get_pixel(bg_x as u32, bg_y as u32);
-                let bg_rgba = bg_pixel.to_rgba();
-
-                // 2. Calculate Difference for each channel: |Background - Text|
-                // Use i16 to prevent underflow during subtraction
-                let r_diff = (bg_rgba[0] as i16 - text_rgba[0] as i16).abs() as u8;
-                let g_diff = (bg_rgba[1] as i16 - text_rgba[1] as i16).abs() as u8;
-                let b_diff = (bg_rgba[2] as i16 - text_rgba[2] as i16).abs() as u8;
-
-                // 3. Alpha Compositing
-                // If the text is semi-transparent (anti-aliased), we blend
-                // between the original background and the differenced result.
-                let alpha_weight = text_rgba[3] as f32 / 255.0;
-
-                let final_r =
-                    (bg_rgba[0] as f32 * (1.0 - alpha_weight) + r_diff as f32 * alpha_weight) as u8;
-                let final_g =
-                    (bg_rgba[1] as f32 * (1.0 - alpha_weight) + g_diff as f32 * alpha_weight) as u8;
-                let final_b =
-                    (bg_rgba[2] as f32 * (1.0 - alpha_weight) + b_diff as f32 * alpha_weight) as u8;
-
-                // 4. Update the background pixel
-                background.put_pixel(
-                    bg_x as u32,
-                    bg_y as u32,
-                    Rgba([final_r, final_g, final_b, bg_rgba[3]]),
-                );
+/// `tile` repeated over a layer of `size` on a grid turned by the
+/// watermark's angle, with one tile where the single watermark would be.
+fn tiled_layer(
+    tile: &RgbaImage,
+    [width, height]: [u32; 2],
+    params: &WatermarkParams,
+    font: &FontVec,
+) -> RgbaImage {
+    let (text_width, text_height) = text_size(params, font, width);
+    let theta = params.degree.to_radians();
+
+    // Keep tiny text with no spacing from producing millions of tiles
+    let pitch = [
+        text_width + params.tile_spacing[0],
+        text_height + params.tile_spacing[1],
+    ]
+    .map(|v| v.max(MIN_TILE_PITCH));
+    let pivot = [
+        (width / 2) as f32 + params.x as f32,
+        (height / 2) as f32 + params.y as f32,
+    ];
+    // Distance from the pivot to the farthest corner, which the grid must reach
+    let reach = [0.0, width as f32]
+        .into_iter()
+        .flat_map(|x| [0.0, height as f32].map(|y| (x - pivot[0]).hypot(y - pivot[1])))
+        .fold(0.0, f32::max);
+    let columns = (reach / pitch[0]).ceil() as i32 + 1;
+    let rows = (reach / pitch[1]).ceil() as i32 + 1;
+
+    let (sin, cos) = theta.sin_cos();
+    let half_tile = [tile.width(), tile.height()].map(|v| (v / 2) as f32);
+    let mut layer = RgbaImage::new(width, height);
+    for row in -rows..=rows {
+        let stagger = if row.rem_euclid(2) == 1 {
+            params.tile_stagger * pitch[0]
+        } else {
+            0.0
+        };
+        for column in -columns..=columns {
+            // Center of the text block before rotation, relative to the pivot
+            let x = column as f32 * pitch[0] + stagger;
+            let y = row as f32 * pitch[1] + text_height / 2.0;
+            let left = pivot[0] + x * cos - y * sin - half_tile[0];
+            let top = pivot[1] + x * sin + y * cos - half_tile[1];
+            if left + tile.width() as f32 <= 0.0
+                || top + tile.height() as f32 <= 0.0
+                || left >= width as f32
+                || top >= height as f32
+            {
+                continue;
             }
+            overlay(&mut layer, tile, left.round() as i64, top.round() as i64);
         }
     }
+    layer
 }
 
-fn _blend_exclusion2<I, J>(background: &mut I, text_image: &J, offset_x: i64, offset_y: i64)
-where
-    I: GenericImage,
-    J: GenericImageView<Pixel = I::Pixel>,
-    I::Pixel: Pixel<Subpixel = u8>,
-{
-    let (text_w, text_h) = text_image.dimensions();
-    let (bg_w, bg_h) = background.dimensions();
-
-    for y in 0..text_h {
-        for x in 0..text_w {
-            let bg_x = offset_x + x as i64;
-            let bg_y = offset_y + y as i64;
-
-            if bg_x >= 0 && bg_x < bg_w as i64 && bg_y >= 0 && bg_y < bg_h as i64 {
-                let text_pixel = text_image.get_pixel(x, y);
-                let text_rgba = text_pixel.to_rgba();
-
-                // Skip if fully transparent
-                if text_rgba[3] == 0 {
-                    continue;
-                }
-
-                let bg_pixel = background.get_pixel(bg_x as u32, bg_y as u32);
-                let bg_rgba = bg_pixel.to_rgba();
-
-                // Exclusion blend logic
-                let blend = |bg: u8, fg: u8| -> u8 {
-                    let bgf = bg as f32;
-                    let fgf = fg as f32;
-                    let res = bgf + fgf - (2.0 * bgf * fgf / 255.0);
-                    res.clamp(0.0, 255.0) as u8
-                };
-
-                let r_excl = blend(bg_rgba[0], text_rgba[0]);
-                let g_excl = blend(bg_rgba[1], text_rgba[1]);
-                let b_excl = blend(bg_rgba[2], text_rgba[2]);
-
-                // Alpha blending (handles anti-aliased text edges)
-                let alpha = text_rgba[3] as f32 / 255.0;
-                let final_r = (bg_rgba[0] as f32 * (1.0 - alpha) + r_excl as f32 * alpha) as u8;
-                let final_g = (bg_rgba[1] as f32 * (1.0 - alpha) + g_excl as f32 * alpha) as u8;
-                let final_b = (bg_rgba[2] as f32 * (1.0 - alpha) + b_excl as f32 * alpha) as u8;
-
-                // Prepare the raw byte array [R, G, B, A]
-                // We keep the background's original alpha (bg_rgba[3])
-                let raw_pixel = [final_r, final_g, final_b, bg_rgba[3]];
-
-                // Use from_slice to create the pixel type generic over I::Pixel
-                let final_pixel = *I::Pixel::from_slice(&raw_pixel);
-
-                background.put_pixel(bg_x as u32, bg_y as u32, final_pixel);
-            }
-        }
+/// Room the outline and shadow need around the text.
+fn decoration_padding(params: &WatermarkParams) -> f32 {
+    let mut pad = params.outline_width.max(0.0);
+    if params.shadow {
+        let offset = params.shadow_offset[0]
+            .abs()
+            .max(params.shadow_offset[1].abs());
+        pad += offset + 3.0 * params.shadow_blur.max(0.0);
+    }
+    pad.ceil() + 1.0
+}
+
+/// Adds the outline and drop shadow `params` ask for to the text `layer`.
+fn decorate(layer: &RgbaImage, params: &WatermarkParams) -> RgbaImage {
+    let mut layer = layer.clone();
+    if params.outline_width >= 0.5 {
+        let radius = params.outline_width.round().min(255.0) as u8;
+        let color = Rgba(params.outline_color.to_srgba_unmultiplied());
+        layer = with_outline(&layer, radius, color);
+    }
+    if params.shadow {
+        let offset = params.shadow_offset.map(|v| v.round() as i64);
+        let color = Rgba(params.shadow_color.to_srgba_unmultiplied());
+        layer = with_shadow(&layer, offset, params.shadow_blur, color);
+    }
+    layer
+}
+
+/// `color` with its alpha scaled by `mask`.
+fn tinted(mask: &GrayImage, color: Rgba<u8>) -> RgbaImage {
+    RgbaImage::from_fn(mask.width(), mask.height(), |x, y| {
+        let alpha = mask.get_pixel(x, y)[0] as u16 * color[3] as u16 / 255;
+        Rgba([color[0], color[1], color[2], alpha as u8])
+    })
+}
+
+fn alpha_mask(layer: &RgbaImage) -> GrayImage {
+    GrayImage::from_fn(layer.width(), layer.height(), |x, y| {
+        Luma([layer.get_pixel(x, y)[3]])
+    })
+}
+
+/// `layer` over its shape grown by `radius` pixels in `color`.
+fn with_outline(layer: &RgbaImage, radius: u8, color: Rgba<u8>) -> RgbaImage {
+    let grown = grayscale_dilate(&alpha_mask(layer), &Mask::disk(radius));
+    let mut outlined = tinted(&grown, color);
+    overlay(&mut outlined, layer, 0, 0);
+    outlined
+}
+
+/// `layer` over its shape in `color`, moved by `offset` and blurred with a
+/// Gaussian of sigma `blur`.
+fn with_shadow(layer: &RgbaImage, offset: [i64; 2], blur: f32, color: Rgba<u8>) -> RgbaImage {
+    let mut mask = alpha_mask(layer);
+    if blur > 0.0 {
+        mask = gaussian_blur_f32(&mask, blur);
     }
+    let mut shadowed = RgbaImage::new(layer.width(), layer.height());
+    overlay(&mut shadowed, &tinted(&mask, color), offset[0], offset[1]);
+    overlay(&mut shadowed, layer, 0, 0);
+    shadowed
 }
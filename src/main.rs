@@ -1,15 +1,46 @@
+use std::process::ExitCode;
+
 use crate::image_editor_ui::ImageEditorUi;
 
+mod batch;
+mod blend_util;
+mod canvas_tools;
+mod color_util;
+mod curve_editor;
+mod curve_util;
+mod export_util;
+mod filter_util;
+mod font_picker;
+mod font_registry;
 mod font_util;
+mod histogram;
+mod histogram_view;
+mod history;
 mod image_editor;
 mod image_editor_ui;
 mod imageproc_util;
+mod pipeline_cache;
+mod preset;
+mod render_worker;
+mod viewer;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("batch") {
+        return batch::run(&args[1..]);
+    }
 
-fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions::default();
-    eframe::run_native(
+    let result = eframe::run_native(
         "Linear Image Editor",
         options,
         Box::new(|cc| Ok(Box::new(ImageEditorUi::new(cc)))),
-    )
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,297 @@
+use std::{
+    hash::{DefaultHasher, Hasher},
+    io,
+    sync::Arc,
+};
+
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::{
+    font_registry::FontRegistry,
+    image_editor::{EffectType, ImageOp},
+};
+
+/// Bytes of step outputs a cache keeps between runs, on top of the final
+/// output and the outputs asked to be kept. About three 20 MP RGBA images.
+pub(crate) const STEP_CACHE_BUDGET: usize = 256 * 1024 * 1024;
+
+struct CachedStep {
+    key: u64,
+    /// `None` once dropped by [`PipelineCache::trim`].
+    output: Option<Arc<DynamicImage>>,
+}
+
+/// Output of the pipeline steps from the last run, so a re-run only redoes
+/// the steps from the first changed, toggled or reordered op onwards, or
+/// from the last step whose output is still cached before it.
+#[derive(Default)]
+pub(crate) struct PipelineCache {
+    source: Option<Arc<DynamicImage>>,
+    steps: Vec<CachedStep>,
+}
+
+impl PipelineCache {
+    pub(crate) fn clear(&mut self) {
+        self.source = None;
+        self.steps.clear();
+    }
+
+    /// The image that went into step `index` of the last run.
+    pub(crate) fn step_input(&self, index: usize) -> Option<&Arc<DynamicImage>> {
+        match index {
+            0 => self.source.as_ref(),
+            _ => self.step_output(index - 1),
+        }
+    }
+
+    /// The image step `index` produced in the last run.
+    pub(crate) fn step_output(&self, index: usize) -> Option<&Arc<DynamicImage>> {
+        self.steps.get(index).and_then(|step| step.output.as_ref())
+    }
+
+    /// Drops step outputs, earliest first, until the rest fit in `budget`
+    /// bytes. The outputs of the last step and of the steps in `keep` stay
+    /// and don't count towards it.
+    pub(crate) fn trim(&mut self, budget: usize, keep: &[usize]) {
+        let last = self.steps.len().saturating_sub(1);
+        let mut used = 0;
+        let mut newest_kept: Option<Arc<DynamicImage>> = None;
+        for (index, step) in self.steps.iter_mut().enumerate().rev() {
+            let Some(output) = &step.output else {
+                continue;
+            };
+            // Bypassed steps share the output of the step before them
+            let shared = newest_kept
+                .as_ref()
+                .is_some_and(|kept| Arc::ptr_eq(kept, output));
+            let size = if shared { 0 } else { output.as_bytes().len() };
+            if index == last || keep.contains(&index) {
+                newest_kept = Some(output.clone());
+            } else if used + size <= budget {
+                used += size;
+                newest_kept = Some(output.clone());
+            } else {
+                step.output = None;
+            }
+        }
+    }
+
+    /// Runs `pipeline` over `source`, reusing the cached prefix that still
+    /// matches. A different `source` (by pointer) invalidates everything.
+    ///
+    /// `is_cancelled` is checked before every step that has to be computed;
+    /// once it returns true the run stops and returns `None`, keeping the
+    /// steps finished so far.
+    pub(crate) fn run_cancellable(
+        &mut self,
+        source: &Arc<DynamicImage>,
+        pipeline: &[ImageOp],
+        is_cancelled: impl Fn() -> bool,
+    ) -> Option<Arc<DynamicImage>> {
+        if !self
+            .source
+            .as_ref()
+            .is_some_and(|cached| Arc::ptr_eq(cached, source))
+        {
+            self.clear();
+            self.source = Some(source.clone());
+        }
+
+        let keys: Vec<u64> = pipeline.iter().map(step_key).collect();
+        let valid = self
+            .steps
+            .iter()
+            .zip(&keys)
+            .take_while(|(step, key)| step.key == **key)
+            .count();
+        self.steps.truncate(valid);
+        let resume = self
+            .steps
+            .iter()
+            .rposition(|step| step.output.is_some())
+            .map_or(0, |index| index + 1);
+        self.steps.truncate(resume);
+
+        let mut img = self.step_input(resume).unwrap_or(source).clone();
+        for (op, key) in pipeline[resume..].iter().zip(&keys[resume..]) {
+            // Bypassed steps share their input instead of copying it
+            if op.enabled {
+                if is_cancelled() {
+                    return None;
+                }
+                img = Arc::new(op.effect.apply((*img).clone()));
+            }
+            self.steps.push(CachedStep {
+                key: *key,
+                output: Some(img.clone()),
+            });
+        }
+        Some(img)
+    }
+}
+
+/// Identifies a whole pipeline, including the order of its ops.
+pub(crate) fn pipeline_key(pipeline: &[ImageOp]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for op in pipeline {
+        hasher.write_u64(step_key(op));
+    }
+    hasher.finish()
+}
+
+/// Identifies an op together with all of its parameters. The op id is part
+/// of the key, so moving an op invalidates its old position too.
+fn step_key(op: &ImageOp) -> u64 {
+    match op.effect {
+        // The font a watermark is drawn with can change as fonts are added
+        EffectType::Watermark { .. } => serialized_key(&(op, FontRegistry::generation())),
+        _ => serialized_key(op),
+    }
+}
+
+/// Hash of the serialized form of `value`, for types holding floats that
+/// can't derive `Hash`.
+pub(crate) fn serialized_key(value: &impl Serialize) -> u64 {
+    let mut hasher = HashWriter(DefaultHasher::new());
+    serde_json::to_writer(&mut hasher, value).expect("value always serializes");
+    hasher.0.finish()
+}
+
+/// Feeds serialized bytes straight into a hasher.
+struct HashWriter(DefaultHasher);
+
+impl io::Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// Bytes of one output of [`new_source`].
+    const IMAGE_BYTES: usize = 8 * 8 * 4;
+
+    fn new_source() -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::new_rgba8(8, 8))
+    }
+
+    fn brightness(id: usize, value: i32) -> ImageOp {
+        ImageOp {
+            id,
+            effect: EffectType::Brightness { value },
+            enabled: true,
+        }
+    }
+
+    fn pipeline(len: usize) -> Vec<ImageOp> {
+        (0..len).map(|id| brightness(id, 10)).collect()
+    }
+
+    /// Runs `pipeline` and returns the number of steps that were computed.
+    fn run(cache: &mut PipelineCache, source: &Arc<DynamicImage>, pipeline: &[ImageOp]) -> usize {
+        let computed = Cell::new(0);
+        cache
+            .run_cancellable(source, pipeline, || {
+                computed.set(computed.get() + 1);
+                false
+            })
+            .expect("never cancelled");
+        computed.get()
+    }
+
+    fn cached(cache: &PipelineCache) -> Vec<bool> {
+        (0..cache.steps.len())
+            .map(|index| cache.step_output(index).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn reruns_from_the_first_changed_op() {
+        let source = new_source();
+        let mut pipeline = pipeline(4);
+        let mut cache = PipelineCache::default();
+        assert_eq!(run(&mut cache, &source, &pipeline), 4);
+        let first = cache.step_output(1).unwrap().clone();
+        assert_eq!(run(&mut cache, &source, &pipeline), 0);
+
+        pipeline[2] = brightness(2, 20);
+        assert_eq!(run(&mut cache, &source, &pipeline), 2);
+        assert!(Arc::ptr_eq(cache.step_output(1).unwrap(), &first));
+
+        pipeline.swap(0, 1);
+        assert_eq!(run(&mut cache, &source, &pipeline), 4);
+        // A new source starts over
+        assert_eq!(run(&mut cache, &new_source(), &pipeline), 4);
+    }
+
+    #[test]
+    fn stops_once_cancelled() {
+        let source = new_source();
+        let pipeline = pipeline(3);
+        let mut cache = PipelineCache::default();
+        let computed = Cell::new(0);
+        let result = cache.run_cancellable(&source, &pipeline, || {
+            computed.set(computed.get() + 1);
+            computed.get() > 2
+        });
+        assert!(result.is_none());
+        assert_eq!(cached(&cache), [true, true]);
+        assert_eq!(run(&mut cache, &source, &pipeline), 1);
+    }
+
+    #[test]
+    fn trim_drops_the_earliest_outputs_over_budget() {
+        let source = new_source();
+        let pipeline = pipeline(5);
+        let mut cache = PipelineCache::default();
+        run(&mut cache, &source, &pipeline);
+
+        // The last output doesn't count towards the budget
+        cache.trim(2 * IMAGE_BYTES, &[]);
+        assert_eq!(cached(&cache), [false, false, true, true, true]);
+        cache.trim(0, &[]);
+        assert_eq!(cached(&cache), [false, false, false, false, true]);
+        // An unchanged pipeline still comes straight from the cache
+        assert_eq!(run(&mut cache, &source, &pipeline), 0);
+    }
+
+    #[test]
+    fn trim_keeps_the_steps_asked_for() {
+        let source = new_source();
+        let mut pipeline = pipeline(5);
+        let mut cache = PipelineCache::default();
+        run(&mut cache, &source, &pipeline);
+
+        cache.trim(IMAGE_BYTES, &[0]);
+        assert_eq!(cached(&cache), [true, false, false, true, true]);
+        // Resumes after the last cached output before the change
+        pipeline[2] = brightness(2, 20);
+        assert_eq!(run(&mut cache, &source, &pipeline), 4);
+    }
+
+    #[test]
+    fn trim_counts_outputs_shared_by_bypassed_steps_once() {
+        let source = new_source();
+        let mut pipeline = pipeline(4);
+        pipeline[2].enabled = false;
+        let mut cache = PipelineCache::default();
+        assert_eq!(run(&mut cache, &source, &pipeline), 3);
+        assert!(Arc::ptr_eq(
+            cache.step_output(1).unwrap(),
+            cache.step_output(2).unwrap()
+        ));
+
+        cache.trim(IMAGE_BYTES, &[]);
+        assert_eq!(cached(&cache), [false, true, true, true]);
+    }
+}
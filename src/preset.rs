@@ -0,0 +1,217 @@
+use std::{error::Error, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::image_editor::ImageOp;
+
+pub(crate) const PRESET_EXTENSION: &str = "ieffects";
+
+/// Schema version written into every preset.
+///
+/// Additive changes (a new effect, a new field with a `#[serde(default)]`)
+/// don't need a bump. Anything that changes how existing data is read does:
+/// keep the old layout around as its own type and convert it in [`migrate`].
+pub(crate) const PRESET_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PresetFormat {
+    Json,
+    Ron,
+}
+
+impl PresetFormat {
+    /// `.ron` files are written as RON, everything else as JSON.
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ron") => PresetFormat::Ron,
+            _ => PresetFormat::Json,
+        }
+    }
+
+    /// Guesses the format from the document itself, so a `.ieffects` file can
+    /// hold either.
+    fn sniff(text: &str) -> Self {
+        if text.trim_start().starts_with('{') {
+            PresetFormat::Json
+        } else {
+            PresetFormat::Ron
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Preset {
+    version: u32,
+    pipeline: Vec<ImageOp>,
+}
+
+/// Reads only the version so the rest can be parsed with the matching layout.
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+pub(crate) fn save_preset(
+    path: &Path,
+    pipeline: &[ImageOp],
+    format: PresetFormat,
+) -> Result<(), Box<dyn Error>> {
+    let preset = Preset {
+        version: PRESET_VERSION,
+        pipeline: pipeline.to_vec(),
+    };
+    let text = match format {
+        PresetFormat::Json => serde_json::to_string_pretty(&preset)?,
+        PresetFormat::Ron => {
+            ron::ser::to_string_pretty(&preset, ron::ser::PrettyConfig::default())?
+        }
+    };
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// Loads the pipeline stored at `path`, upgrading it from older schema
+/// versions. The ops still carry the ids they were saved with, which
+/// `ImageEditor::set_pipeline` replaces with fresh ones.
+pub(crate) fn load_preset(path: &Path) -> Result<Vec<ImageOp>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let format = PresetFormat::sniff(&text);
+    let probe: VersionProbe = parse(&text, format)?;
+    migrate(probe.version, &text, format)
+}
+
+/// Forward-migration hook: parses a document of schema `version` and brings
+/// it up to the current layout.
+fn migrate(version: u32, text: &str, format: PresetFormat) -> Result<Vec<ImageOp>, Box<dyn Error>> {
+    match version {
+        PRESET_VERSION => Ok(parse::<Preset>(text, format)?.pipeline),
+        // Retired layouts go here, e.g.
+        // 1 => Ok(parse::<PresetV1>(text, format)?.upgrade()),
+        v if v > PRESET_VERSION => Err(format!(
+            "preset version {v} was written by a newer version of this app \
+             (supported: {PRESET_VERSION})"
+        )
+        .into()),
+        v => Err(format!("unknown preset version {v}").into()),
+    }
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(
+    text: &str,
+    format: PresetFormat,
+) -> Result<T, Box<dyn Error>> {
+    Ok(match format {
+        PresetFormat::Json => serde_json::from_str(text)?,
+        PresetFormat::Ron => ron::from_str(text)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use crate::image_editor::{EffectType, WatermarkParams};
+
+    fn sample_pipeline() -> Vec<ImageOp> {
+        vec![
+            ImageOp {
+                id: 3,
+                effect: EffectType::Blur { sigma: 2.5 },
+                enabled: false,
+            },
+            ImageOp {
+                id: 7,
+                effect: EffectType::Watermark {
+                    params: WatermarkParams {
+                        text: "Draft\n2026".to_string(),
+                        variations: [("wdth".to_string(), 87.5)].into(),
+                        ..WatermarkParams::default()
+                    },
+                },
+                enabled: true,
+            },
+        ]
+    }
+
+    /// Ops compare by id only, so compare everything they serialize to.
+    fn to_value(pipeline: &[ImageOp]) -> serde_json::Value {
+        serde_json::to_value(pipeline).unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("preset-test-{}-{name}", std::process::id()))
+    }
+
+    fn round_trip(name: &str, format: PresetFormat) {
+        let path = temp_path(name);
+        let pipeline = sample_pipeline();
+        save_preset(&path, &pipeline, format).unwrap();
+        let loaded = load_preset(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(to_value(&loaded.unwrap()), to_value(&pipeline));
+    }
+
+    #[test]
+    fn round_trips_as_ron() {
+        round_trip("round-trip.ron", PresetFormat::Ron);
+    }
+
+    #[test]
+    fn round_trips_as_json() {
+        round_trip("round-trip.ieffects", PresetFormat::Json);
+    }
+
+    #[test]
+    fn sniffs_the_format_of_the_document() {
+        // RON in a file whose extension doesn't say so
+        round_trip("ron-inside.ieffects", PresetFormat::Ron);
+        assert_eq!(
+            PresetFormat::from_path(Path::new("a.RON")),
+            PresetFormat::Ron
+        );
+        assert_eq!(
+            PresetFormat::from_path(Path::new("a.ieffects")),
+            PresetFormat::Json
+        );
+    }
+
+    #[test]
+    fn fills_in_fields_older_presets_lack() {
+        // Written before bypassing and font files existed
+        let text = r#"{"version":1,"pipeline":[
+            {"id":0,"effect":{"Watermark":{"params":{"text":"hi","scale":40.0}}}}
+        ]}"#;
+        let pipeline = migrate(1, text, PresetFormat::Json).unwrap();
+        assert!(pipeline[0].enabled);
+        let EffectType::Watermark { params } = &pipeline[0].effect else {
+            panic!("expected a watermark");
+        };
+        let expected = WatermarkParams {
+            text: "hi".to_string(),
+            scale: 40.0,
+            ..WatermarkParams::default()
+        };
+        assert_eq!(params, &expected);
+    }
+
+    #[test]
+    fn rejects_versions_it_does_not_know() {
+        let future = format!(r#"{{"version":{},"pipeline":[]}}"#, PRESET_VERSION + 1);
+        let err = migrate(PRESET_VERSION + 1, &future, PresetFormat::Json)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("newer version"), "{err}");
+
+        let path = temp_path("future.ieffects");
+        fs::write(&path, &future).unwrap();
+        let loaded = load_preset(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+
+        let err = migrate(0, r#"{"version":0,"pipeline":[]}"#, PresetFormat::Json)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown preset version"), "{err}");
+    }
+}
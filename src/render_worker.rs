@@ -0,0 +1,375 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    iter,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+    },
+    thread,
+};
+
+use eframe::egui;
+use image::{DynamicImage, imageops::FilterType};
+
+use crate::{
+    export_util::ExportOptions,
+    histogram::{Histogram, HistogramAt},
+    image_editor::{ImageEditor, ImageOp},
+    pipeline_cache::{PipelineCache, STEP_CACHE_BUDGET, pipeline_key},
+};
+
+struct RenderJob {
+    generation: u64,
+    source: Arc<DynamicImage>,
+    pipeline: Vec<ImageOp>,
+    proxy_size: Option<[u32; 2]>,
+    histograms: Vec<HistogramAt>,
+}
+
+struct ExportJob {
+    editor: ImageEditor,
+    path: PathBuf,
+    options: ExportOptions,
+}
+
+enum Job {
+    Render(RenderJob),
+    Export(ExportJob),
+}
+
+/// What the worker reports back, see [`RenderWorker::poll`].
+pub(crate) enum WorkerEvent {
+    Rendered(RenderResult),
+    /// An export finished writing `path`, or failed with the message.
+    Exported(PathBuf, Result<(), String>),
+    /// A job panicked, or the worker thread is gone.
+    Failed(String),
+}
+
+pub(crate) struct RenderResult {
+    pub(crate) image: Arc<DynamicImage>,
+    /// [`pipeline_key`] of the pipeline that produced `image`.
+    pub(crate) pipeline_key: u64,
+    /// Size of `image` relative to the full-resolution render; below 1.0
+    /// for proxy renders.
+    pub(crate) scale: f32,
+    /// The histograms asked for that could be taken; ops that aren't part
+    /// of the rendered pipeline have none.
+    pub(crate) histograms: HashMap<HistogramAt, Arc<Histogram>>,
+}
+
+/// Downscaled copy of a source image, rebuilt when the source or the
+/// requested size changes.
+#[derive(Default)]
+struct Proxy {
+    source: Option<Arc<DynamicImage>>,
+    size: [u32; 2],
+    image: Option<(Arc<DynamicImage>, f32)>,
+}
+
+impl Proxy {
+    /// Returns `source` fitted into `size` and the scale factor used. Sources
+    /// that already fit are returned as they are.
+    fn get(&mut self, source: &Arc<DynamicImage>, size: [u32; 2]) -> (Arc<DynamicImage>, f32) {
+        let same_source = self
+            .source
+            .as_ref()
+            .is_some_and(|cached| Arc::ptr_eq(cached, source));
+        if !same_source || self.size != size {
+            let scale = (size[0] as f32 / source.width() as f32)
+                .min(size[1] as f32 / source.height() as f32);
+            self.image = (scale < 1.0).then(|| {
+                let width = ((source.width() as f32 * scale).round() as u32).max(1);
+                let height = ((source.height() as f32 * scale).round() as u32).max(1);
+                let image = source.resize_exact(width, height, FilterType::Triangle);
+                (Arc::new(image), scale)
+            });
+            self.source = Some(source.clone());
+            self.size = size;
+        }
+        self.image.clone().unwrap_or_else(|| (source.clone(), 1.0))
+    }
+}
+
+/// State the worker thread keeps between jobs.
+#[derive(Default)]
+struct Worker {
+    // Separate caches so switching between proxy and full renders doesn't
+    // throw away either one
+    full_cache: PipelineCache,
+    proxy_cache: PipelineCache,
+    proxy: Proxy,
+    /// Last histogram per point with the image it was taken of, reused while
+    /// that image stays the same.
+    histograms: HashMap<HistogramAt, (Arc<DynamicImage>, Arc<Histogram>)>,
+}
+
+impl Worker {
+    /// Runs `f` on the worker, turning a panic into an error message. The
+    /// caches it may have left half-updated are thrown away.
+    fn guarded<T>(&mut self, f: impl FnOnce(&mut Worker) -> T) -> Result<T, String> {
+        panic::catch_unwind(AssertUnwindSafe(|| f(self))).map_err(|payload| {
+            *self = Worker::default();
+            panic_message(&*payload)
+        })
+    }
+
+    /// Renders `job`, or returns `None` once `is_stale` says it has been
+    /// superseded.
+    fn render(&mut self, job: RenderJob, is_stale: impl Fn() -> bool) -> Option<RenderResult> {
+        let key = pipeline_key(&job.pipeline);
+        let (source, scale) = match job.proxy_size {
+            Some(size) => self.proxy.get(&job.source, size),
+            None => (job.source.clone(), 1.0),
+        };
+        let (cache, pipeline) = if scale < 1.0 {
+            let pipeline = job
+                .pipeline
+                .iter()
+                .map(|op| ImageOp {
+                    effect: op.effect.scaled(scale),
+                    ..op.clone()
+                })
+                .collect();
+            (&mut self.proxy_cache, pipeline)
+        } else {
+            (&mut self.full_cache, job.pipeline)
+        };
+
+        let index = |id| pipeline.iter().position(|op| op.id == id);
+        // Steps whose outputs the histograms are taken of
+        let keep: Vec<usize> = job
+            .histograms
+            .iter()
+            .filter_map(|at| match *at {
+                HistogramAt::Input(id) => index(id)?.checked_sub(1),
+                HistogramAt::Output(id) => index(id),
+                HistogramAt::Final => None,
+            })
+            .collect();
+
+        let image = cache.run_cancellable(&source, &pipeline, is_stale);
+        let Some(image) = image else {
+            cache.trim(STEP_CACHE_BUDGET, &keep);
+            return None;
+        };
+
+        let histograms = &mut self.histograms;
+        histograms.retain(|at, _| job.histograms.contains(at));
+        for at in &job.histograms {
+            let taken_of = match *at {
+                HistogramAt::Input(id) => index(id).and_then(|i| cache.step_input(i)),
+                HistogramAt::Output(id) => index(id).and_then(|i| cache.step_output(i)),
+                HistogramAt::Final => Some(&image),
+            };
+            let Some(taken_of) = taken_of else {
+                histograms.remove(at);
+                continue;
+            };
+            match histograms.get(at) {
+                Some((image, _)) if Arc::ptr_eq(image, taken_of) => {}
+                _ => {
+                    let histogram = Arc::new(Histogram::from_image(taken_of));
+                    histograms.insert(*at, (taken_of.clone(), histogram));
+                }
+            }
+        }
+
+        cache.trim(STEP_CACHE_BUDGET, &keep);
+
+        Some(RenderResult {
+            image,
+            pipeline_key: key,
+            scale,
+            histograms: histograms
+                .iter()
+                .map(|(at, (_, histogram))| (*at, histogram.clone()))
+                .collect(),
+        })
+    }
+
+    /// Exports `job` through [`ImageEditor::export`], rendering it at full
+    /// resolution first with the cached steps if the editor hasn't.
+    fn export(&mut self, mut job: ExportJob) -> Result<(), String> {
+        let editor = &mut job.editor;
+        if editor.current_final_image().is_none() {
+            let image = self
+                .full_cache
+                .run_cancellable(&editor.original_image, &editor.pipeline, || false)
+                .expect("never cancelled");
+            self.full_cache.trim(STEP_CACHE_BUDGET, &[]);
+            editor.set_final_image(image, pipeline_key(&editor.pipeline));
+        }
+        editor
+            .export(&job.path, job.options)
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+/// Runs the pipeline on a background thread so the UI keeps drawing while
+/// heavy effects render.
+///
+/// Only the newest render matters: queued renders are skipped in favour of
+/// later ones, and a running render stops at the next step boundary once a
+/// newer one has been submitted. Steps finished before that stay in the
+/// cache. Exports are never skipped.
+///
+/// A job that panics is reported as [`WorkerEvent::Failed`] and the worker
+/// carries on with empty caches.
+pub(crate) struct RenderWorker {
+    jobs: Sender<Job>,
+    /// Events tagged with the generation of the render they belong to.
+    events: Receiver<(Option<u64>, WorkerEvent)>,
+    latest: Arc<AtomicU64>,
+    busy: bool,
+    /// Set once the worker thread was found gone.
+    stopped: bool,
+}
+
+impl RenderWorker {
+    pub(crate) fn spawn(ctx: egui::Context) -> Self {
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let (event_tx, events) = mpsc::channel();
+        let latest = Arc::new(AtomicU64::new(0));
+
+        let latest_generation = latest.clone();
+        thread::Builder::new()
+            .name("render-worker".to_string())
+            .spawn(move || {
+                let mut worker = Worker::default();
+                while let Ok(job) = job_rx.recv() {
+                    let mut render = None;
+                    for job in iter::once(job).chain(job_rx.try_iter()) {
+                        match job {
+                            Job::Render(job) => render = Some(job),
+                            Job::Export(job) => {
+                                let path = job.path.clone();
+                                let result = worker.guarded(|worker| worker.export(job));
+                                let event = WorkerEvent::Exported(path, result.and_then(|r| r));
+                                if event_tx.send((None, event)).is_err() {
+                                    return;
+                                }
+                                ctx.request_repaint();
+                            }
+                        }
+                    }
+                    let Some(job) = render else {
+                        continue;
+                    };
+
+                    let generation = job.generation;
+                    let is_stale = || latest_generation.load(Ordering::Relaxed) != generation;
+                    let event = match worker.guarded(|worker| worker.render(job, is_stale)) {
+                        Ok(Some(result)) => WorkerEvent::Rendered(result),
+                        Ok(None) => continue,
+                        Err(message) => WorkerEvent::Failed(message),
+                    };
+                    if event_tx.send((Some(generation), event)).is_err() {
+                        return;
+                    }
+                    ctx.request_repaint();
+                }
+            })
+            .expect("failed to spawn render worker");
+
+        Self {
+            jobs,
+            events,
+            latest,
+            busy: false,
+            stopped: false,
+        }
+    }
+
+    /// Queues a render, superseding any render that hasn't finished yet.
+    ///
+    /// With a `proxy_size` (in pixels) the pipeline runs on a copy of
+    /// `source` fitted into that size, with its parameters scaled to match.
+    /// The result carries the histograms asked for in `histograms`.
+    pub(crate) fn submit(
+        &mut self,
+        source: Arc<DynamicImage>,
+        pipeline: Vec<ImageOp>,
+        proxy_size: Option<[u32; 2]>,
+        histograms: Vec<HistogramAt>,
+    ) {
+        let generation = self.latest.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Job::Render(RenderJob {
+            generation,
+            source,
+            pipeline,
+            proxy_size,
+            histograms,
+        });
+        // A worker that is gone gets reported by `poll`
+        self.busy = self.jobs.send(job).is_ok();
+    }
+
+    /// Queues [`ImageEditor::export`] on a snapshot of `editor`, so the UI
+    /// doesn't wait for the full-resolution render. The outcome arrives as
+    /// [`WorkerEvent::Exported`].
+    pub(crate) fn export(
+        &mut self,
+        editor: ImageEditor,
+        path: PathBuf,
+        options: ExportOptions,
+    ) -> Result<(), String> {
+        self.jobs
+            .send(Job::Export(ExportJob {
+                editor,
+                path,
+                options,
+            }))
+            .map_err(|_| "the render worker stopped".to_string())
+    }
+
+    /// Events since the last poll: finished exports, failures and, once it
+    /// is done, the result of the latest render. Superseded renders are
+    /// dropped.
+    pub(crate) fn poll(&mut self) -> Vec<WorkerEvent> {
+        let latest = self.latest.load(Ordering::Relaxed);
+        let mut events = vec![];
+        let mut render = None;
+        loop {
+            match self.events.try_recv() {
+                Ok((None, event)) => events.push(event),
+                Ok((Some(generation), event)) => {
+                    if generation == latest {
+                        render = Some(event);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !self.stopped {
+                        self.stopped = true;
+                        events.push(WorkerEvent::Failed("the render worker stopped".to_string()));
+                    }
+                    self.busy = false;
+                    break;
+                }
+            }
+        }
+        if let Some(event) = render {
+            self.busy = false;
+            events.push(event);
+        }
+        events
+    }
+
+    /// Whether the latest submitted render is still running.
+    pub(crate) fn is_busy(&self) -> bool {
+        self.busy
+    }
+}
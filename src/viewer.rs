@@ -0,0 +1,222 @@
+use eframe::egui::{self, Color32, Pos2, Rect, Sense, Vec2};
+
+const MIN_ZOOM: f32 = 0.01;
+const MAX_ZOOM: f32 = 64.0;
+/// Zoom (screen points per image pixel) from which the pixel grid is drawn.
+const GRID_ZOOM: f32 = 8.0;
+
+/// How the original image is shown next to the edited one.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum CompareMode {
+    #[default]
+    Off,
+    /// Original left of a draggable divider, edited image right of it.
+    Split,
+    SideBySide,
+}
+
+impl CompareMode {
+    pub(crate) const ALL: [CompareMode; 3] = [
+        CompareMode::Off,
+        CompareMode::Split,
+        CompareMode::SideBySide,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            CompareMode::Off => "Off",
+            CompareMode::Split => "Split",
+            CompareMode::SideBySide => "Side by side",
+        }
+    }
+}
+
+/// Zoomable, pannable view of an image.
+///
+/// Coordinates are in image pixels of the full-resolution image, whatever
+/// the resolution of the texture being drawn. The view can be split into
+/// panes side by side that all show the same region.
+pub(crate) struct Viewer {
+    /// Screen points per image pixel.
+    zoom: f32,
+    /// Offset of the image center from the viewport center, in points.
+    pan: Vec2,
+    fit_pending: bool,
+    /// Where the first pane was drawn last frame.
+    viewport: Rect,
+    panes: usize,
+}
+
+impl Default for Viewer {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            pan: Vec2::ZERO,
+            fit_pending: true,
+            viewport: Rect::NOTHING,
+            panes: 1,
+        }
+    }
+}
+
+impl Viewer {
+    /// Fits the image into the view on the next frame.
+    pub(crate) fn fit(&mut self) {
+        self.fit_pending = true;
+    }
+
+    /// Shows one image pixel per screen pixel, centered.
+    pub(crate) fn actual_size(&mut self, pixels_per_point: f32) {
+        self.zoom = 1.0 / pixels_per_point;
+        self.pan = Vec2::ZERO;
+        self.fit_pending = false;
+    }
+
+    pub(crate) fn zoom(&self) -> f32 {
+        self.zoom
+    }
+
+    pub(crate) fn image_to_screen(&self, image_size: Vec2, pos: Pos2) -> Pos2 {
+        self.viewport.center() + self.pan + (pos.to_vec2() - image_size / 2.0) * self.zoom
+    }
+
+    pub(crate) fn screen_to_image(&self, image_size: Vec2, pos: Pos2) -> Pos2 {
+        ((pos - self.viewport.center() - self.pan) / self.zoom + image_size / 2.0).to_pos2()
+    }
+
+    pub(crate) fn image_rect(&self, image_size: Vec2) -> Rect {
+        Rect::from_min_max(
+            self.image_to_screen(image_size, Pos2::ZERO),
+            self.image_to_screen(image_size, image_size.to_pos2()),
+        )
+    }
+
+    /// Screen rect of pane `index`.
+    pub(crate) fn pane_rect(&self, index: usize) -> Rect {
+        self.viewport
+            .translate(Vec2::X * self.viewport.width() * index as f32)
+    }
+
+    /// Maps `pos` in any pane to the same spot in the first pane.
+    fn to_first_pane(&self, pos: Pos2) -> Pos2 {
+        let index = ((pos.x - self.viewport.left()) / self.viewport.width())
+            .floor()
+            .clamp(0.0, (self.panes - 1) as f32);
+        pos - Vec2::X * self.viewport.width() * index
+    }
+
+    /// The image pixel under `pos`, if `pos` is inside the view and the image.
+    pub(crate) fn pixel_at(&self, image_size: Vec2, pos: Option<Pos2>) -> Option<[u32; 2]> {
+        let area = self.viewport.union(self.pane_rect(self.panes - 1));
+        let pos = pos.filter(|pos| area.contains(*pos))?;
+        let pixel = self.screen_to_image(image_size, self.to_first_pane(pos));
+        let inside =
+            pixel.x >= 0.0 && pixel.y >= 0.0 && pixel.x < image_size.x && pixel.y < image_size.y;
+        inside.then_some([pixel.x as u32, pixel.y as u32])
+    }
+
+    /// Lays out the view over the remaining space, split into `panes` equal
+    /// columns, and handles zooming and panning. Draw into it with
+    /// [`Viewer::paint_image`].
+    pub(crate) fn interact(
+        &mut self,
+        ui: &mut egui::Ui,
+        image_size: Vec2,
+        panes: usize,
+    ) -> egui::Response {
+        let (rect, response) = ui.allocate_exact_size(ui.available_size(), Sense::click_and_drag());
+        self.panes = panes.max(1);
+        self.viewport = Rect::from_min_size(
+            rect.min,
+            rect.size() * Vec2::new(1.0 / self.panes as f32, 1.0),
+        );
+
+        if self.fit_pending && image_size.x > 0.0 && image_size.y > 0.0 {
+            self.zoom = (self.viewport.width() / image_size.x)
+                .min(self.viewport.height() / image_size.y)
+                .clamp(MIN_ZOOM, MAX_ZOOM);
+            self.pan = Vec2::ZERO;
+            self.fit_pending = false;
+        }
+
+        if response.dragged_by(egui::PointerButton::Primary)
+            || response.dragged_by(egui::PointerButton::Middle)
+        {
+            self.pan += response.drag_delta();
+        }
+
+        if let Some(pointer) = response.hover_pos() {
+            // Plain wheel scrolls, Ctrl+wheel and pinch arrive as zoom_delta
+            let (scroll, zoom_delta) = ui.input(|i| (i.smooth_scroll_delta.y, i.zoom_delta()));
+            let factor = zoom_delta * (scroll * 0.002).exp();
+            if factor != 1.0 {
+                self.zoom_around(image_size, self.to_first_pane(pointer), factor);
+            }
+        }
+
+        response
+    }
+
+    /// Zooms by `factor`, keeping the image point under `pointer` in place.
+    fn zoom_around(&mut self, image_size: Vec2, pointer: Pos2, factor: f32) {
+        let anchor = self.screen_to_image(image_size, pointer);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.pan =
+            pointer - self.viewport.center() - (anchor.to_vec2() - image_size / 2.0) * self.zoom;
+    }
+
+    /// Draws `texture` stretched over the image area of pane `pane`,
+    /// clipped to `clip`.
+    pub(crate) fn paint_image(
+        &self,
+        ui: &egui::Ui,
+        pane: usize,
+        clip: Rect,
+        texture: &egui::TextureHandle,
+        image_size: Vec2,
+    ) {
+        let offset = self.pane_rect(pane).min - self.viewport.min;
+        let painter = ui.painter_at(clip.intersect(self.pane_rect(pane)));
+        painter.image(
+            texture.id(),
+            self.image_rect(image_size).translate(offset),
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+            Color32::WHITE,
+        );
+        if self.zoom >= GRID_ZOOM {
+            self.paint_pixel_grid(&painter, image_size, offset);
+        }
+    }
+
+    fn paint_pixel_grid(&self, painter: &egui::Painter, image_size: Vec2, offset: Vec2) {
+        let visible = painter.clip_rect().translate(-offset);
+        let min = self.screen_to_image(image_size, visible.min);
+        let max = self.screen_to_image(image_size, visible.max);
+        let stroke = egui::Stroke::new(1.0, Color32::from_gray(128).gamma_multiply(0.5));
+
+        let (x0, x1) = (min.x.floor().max(0.0), max.x.ceil().min(image_size.x));
+        let (y0, y1) = (min.y.floor().max(0.0), max.y.ceil().min(image_size.y));
+        let mut x = x0;
+        while x <= x1 {
+            painter.line_segment(
+                [
+                    self.image_to_screen(image_size, Pos2::new(x, y0)) + offset,
+                    self.image_to_screen(image_size, Pos2::new(x, y1)) + offset,
+                ],
+                stroke,
+            );
+            x += 1.0;
+        }
+        let mut y = y0;
+        while y <= y1 {
+            painter.line_segment(
+                [
+                    self.image_to_screen(image_size, Pos2::new(x0, y)) + offset,
+                    self.image_to_screen(image_size, Pos2::new(x1, y)) + offset,
+                ],
+                stroke,
+            );
+            y += 1.0;
+        }
+    }
+}